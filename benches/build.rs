@@ -0,0 +1,95 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use criterion::{Criterion, criterion_group, criterion_main};
+use mkdbupgrade::{MkdbError, UpgradeEntry, UpgradeScriptBuilder, UpgradeSource};
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+/// Upgrade files in a temporary directory, all new in "to"
+struct TempSource {
+    dir: TempDir,
+    names: Vec<String>,
+}
+
+impl TempSource {
+    /// Write count upgrade files of about 4 KiB each
+    fn new(count: usize) -> TempSource {
+        let dir = tempfile::tempdir().unwrap();
+        let body = "INSERT INTO config.org_unit_setting_type (name) VALUES ('example');\n".repeat(60);
+        let names: Vec<String> = (0..count).map(|n| format!("{n:04}.data.example.sql")).collect();
+        for name in &names {
+            std::fs::write(dir.path().join(name), format!("BEGIN;\n{body}COMMIT;\n")).unwrap();
+        }
+        TempSource { dir, names }
+    }
+}
+
+impl UpgradeSource for &TempSource {
+    type Ref = bool;
+
+    fn upgrades(&self, to: &bool) -> Result<Vec<UpgradeEntry>, MkdbError> {
+        let names = if *to { &self.names[..] } else { &[] };
+        Ok(names.iter().map(|n| UpgradeEntry { path: n.clone(), oid: String::new() }).collect())
+    }
+
+    fn version(&self, to: &bool) -> Result<String, MkdbError> {
+        Ok(if *to { "3.15.0" } else { "3.14.0" }.to_string())
+    }
+
+    fn file_path(&self, _to: &bool, path: &str) -> String {
+        let path: PathBuf = self.dir.path().join(path);
+        path.to_string_lossy().into_owned()
+    }
+}
+
+fn build(c: &mut Criterion) {
+    for count in [10, 1000] {
+        let source = TempSource::new(count);
+        c.bench_function(&format!("build_to {count} files"), |b| {
+            b.iter(|| {
+                let mut out: Vec<u8> = Vec::new();
+                UpgradeScriptBuilder::with_source(&source, false, true).build_to(&mut out).unwrap();
+                out
+            })
+        });
+    }
+}
+
+/// Compare sequential and parallel reads around PARALLEL_THRESHOLD
+fn threshold(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parallel_threshold");
+    for count in [8, 16, 32, 64, 128] {
+        let source = TempSource::new(count);
+        group.bench_function(format!("{count} files sequential"), |b| b.iter(|| {
+            let mut out: Vec<u8> = Vec::new();
+            UpgradeScriptBuilder::with_source(&source, false, true).jobs(1).build_to(&mut out).unwrap();
+            out
+        }));
+        group.bench_function(format!("{count} files parallel"), |b| b.iter(|| {
+            let mut out: Vec<u8> = Vec::new();
+            UpgradeScriptBuilder::with_source(&source, false, true).parallel_threshold(0).build_to(&mut out).unwrap();
+            out
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, build, threshold);
+criterion_main!(benches);
@@ -0,0 +1,84 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use criterion::{Criterion, criterion_group, criterion_main};
+use mkdbupgrade::{Revision, clear_upgrade_cache, find_branch, get_branch_upgrades, get_upgrades};
+use std::hint::black_box;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+use common::{FROM_BRANCH, Fixture, TO_BRANCH};
+
+/// Make upgrade files numbered from start up to end
+fn upgrade_files(start: usize, end: usize) -> Vec<(String, String)> {
+    (start..end).map(|n| {
+        (format!("{:04}.schema.example.sql", n), format!("BEGIN;\nSELECT {};\nCOMMIT;\n", n))
+    }).collect()
+}
+
+/// Borrow upgrade files in the form that Fixture takes
+fn as_refs(files: &[(String, String)]) -> Vec<(&str, &str)> {
+    files.iter().map(|(name, content)| (name.as_str(), content.as_str())).collect()
+}
+
+fn upgrades(c: &mut Criterion) {
+    // About the size of Evergreen's upgrade directory over a few
+    // releases.
+    let from = upgrade_files(0, 5000);
+    let to = upgrade_files(0, 5200);
+    let fixture = Fixture::with_upgrades(&as_refs(&from), &as_refs(&to));
+    let repo = &fixture.repo;
+    let from = Revision::Branch(find_branch(repo, FROM_BRANCH).unwrap());
+    let to = Revision::Branch(find_branch(repo, TO_BRANCH).unwrap());
+    c.bench_function("get_upgrades", |b| b.iter(|| {
+        clear_upgrade_cache();
+        get_upgrades(repo, black_box(&from), black_box(&to)).unwrap()
+    }));
+    // Once both directories have been listed, the listings are compared.
+    get_branch_upgrades(repo, &from).unwrap();
+    get_branch_upgrades(repo, &to).unwrap();
+    c.bench_function("get_upgrades_cached", |b| b.iter(|| {
+        get_upgrades(repo, black_box(&from), black_box(&to)).unwrap()
+    }));
+}
+
+/// Make upgrade files in subdirectories, with count files in each
+fn nested_files(dirs: usize, count: usize) -> Vec<(String, String)> {
+    (0..dirs).flat_map(|d| {
+        (0..count).map(move |n| (format!("{:02}/{:04}.schema.example.sql", d, n), format!("SELECT {};\n", n)))
+    }).collect()
+}
+
+fn shared(c: &mut Criterion) {
+    // Only the last subdirectory differs, so the rest are skipped.
+    let from = nested_files(50, 100);
+    let mut to = from.clone();
+    to.push(("49/9999.schema.example.sql".to_string(), "SELECT 9999;\n".to_string()));
+    let fixture = Fixture::with_upgrades(&as_refs(&from), &as_refs(&to));
+    let repo = &fixture.repo;
+    let from = Revision::Branch(find_branch(repo, FROM_BRANCH).unwrap());
+    let to = Revision::Branch(find_branch(repo, TO_BRANCH).unwrap());
+    c.bench_function("get_upgrades_shared", |b| b.iter(|| {
+        clear_upgrade_cache();
+        get_upgrades(repo, black_box(&from), black_box(&to)).unwrap()
+    }));
+}
+
+criterion_group!(benches, upgrades, shared);
+criterion_main!(benches);
@@ -0,0 +1,42 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use criterion::{Criterion, criterion_group, criterion_main};
+use mkdbupgrade::{UPGRADE_DIR, UpgradeEntry, diff_upgrades, new_upgrades};
+use std::hint::black_box;
+
+/// Make a listing of upgrades numbered from start up to end
+fn listing(start: usize, end: usize) -> Vec<UpgradeEntry> {
+    (start..end).map(|n| UpgradeEntry {
+        path: format!("{}/{:04}.schema.example.sql", UPGRADE_DIR, n),
+        oid: format!("{:040x}", n),
+    }).collect()
+}
+
+fn compare(c: &mut Criterion) {
+    // About the size of Evergreen's upgrade directory over a few
+    // releases.
+    let from = listing(0, 5000);
+    let to = listing(0, 5200);
+    c.bench_function("new_upgrades", |b| b.iter(|| new_upgrades(black_box(&from), black_box(&to))));
+    c.bench_function("diff_upgrades", |b| b.iter(|| diff_upgrades(black_box(&from), black_box(&to))));
+}
+
+criterion_group!(benches, compare);
+criterion_main!(benches);
@@ -0,0 +1,94 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use criterion::{Criterion, criterion_group, criterion_main};
+use mkdbupgrade::normalize_version;
+#[cfg(feature = "git")]
+use mkdbupgrade::{Revision, find_branch, find_tag, get_revision_version};
+use std::hint::black_box;
+
+#[cfg(feature = "git")]
+#[path = "../tests/common/mod.rs"]
+mod common;
+#[cfg(feature = "git")]
+use common::{FROM_BRANCH, Fixture, TO_BRANCH};
+
+const VERSIONS: &[&str] = &["3.14.0", "3.14.1", "3.15.4", "rel_3_14_0", "rel_3_15_4", "3_13_9"];
+
+fn normalize(c: &mut Criterion) {
+    c.bench_function("normalize_version", |b| b.iter(|| {
+        for v in VERSIONS {
+            black_box(normalize_version(black_box(v)));
+        }
+    }));
+    // What normalize_version would cost if it always allocated.
+    c.bench_function("normalize_version_owned", |b| b.iter(|| {
+        for v in VERSIONS {
+            black_box(normalize_version(black_box(v)).map(|v| v.into_owned()));
+        }
+    }));
+}
+
+/// Make branch names for every release from 2.0.0 to 3.99.9
+fn branch_names() -> Vec<String> {
+    (2..4).flat_map(|x| (0..100).flat_map(move |y| (0..10).map(move |z| format!("rel_{}_{}_{}", x, y, z)))).collect()
+}
+
+fn branches(c: &mut Criterion) {
+    let names = branch_names();
+    c.bench_function("normalize_version_branches", |b| b.iter(|| {
+        for name in &names {
+            black_box(normalize_version(black_box(name)));
+        }
+    }));
+}
+
+/// Get the versions of real branches and tags, as the program does
+#[cfg(feature = "git")]
+fn revisions(c: &mut Criterion) {
+    let fixture = Fixture::new();
+    let repo = &fixture.repo;
+    let commit = find_branch(repo, FROM_BRANCH).unwrap().get().peel_to_commit().unwrap();
+    let names = branch_names();
+    // The fixture already has both of its branches and a tag for one.
+    for name in names.iter().filter(|n| *n != FROM_BRANCH && *n != TO_BRANCH) {
+        repo.branch(name, &commit, false).unwrap();
+        repo.tag_lightweight(name, commit.as_object(), false).unwrap();
+    }
+    let to = find_branch(repo, TO_BRANCH).unwrap().get().peel_to_commit().unwrap();
+    repo.tag_lightweight(TO_BRANCH, to.as_object(), false).unwrap();
+    let branches: Vec<_> = names.iter().map(|n| Revision::Branch(find_branch(repo, n).unwrap())).collect();
+    let tags: Vec<_> = names.iter().map(|n| Revision::Tag(find_tag(repo, n).unwrap())).collect();
+    c.bench_function("get_revision_version_branches", |b| b.iter(|| {
+        for rev in &branches {
+            black_box(get_revision_version(black_box(rev)));
+        }
+    }));
+    c.bench_function("get_revision_version_tags", |b| b.iter(|| {
+        for rev in &tags {
+            black_box(get_revision_version(black_box(rev)));
+        }
+    }));
+}
+
+#[cfg(feature = "git")]
+criterion_group!(benches, normalize, branches, revisions);
+#[cfg(not(feature = "git"))]
+criterion_group!(benches, normalize, branches);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use criterion::{Criterion, criterion_group, criterion_main};
+use mkdbupgrade::write_upgrade;
+use std::hint::black_box;
+use tempfile::NamedTempFile;
+
+/// Write an upgrade of about 16 MiB, like a big data migration
+fn large_upgrade() -> NamedTempFile {
+    let file = NamedTempFile::new().unwrap();
+    let row = "INSERT INTO config.org_unit_setting_type (name) VALUES ('example');\n";
+    let body = row.repeat(16 * 1024 * 1024 / row.len());
+    std::fs::write(file.path(), format!("BEGIN;\n{body}COMMIT;\n")).unwrap();
+    file
+}
+
+fn write(c: &mut Criterion) {
+    let file = large_upgrade();
+    let path = file.path().to_str().unwrap();
+    c.bench_function("write_upgrade", |b| b.iter(|| {
+        let mut out: Vec<u8> = Vec::new();
+        write_upgrade(&mut out, black_box(path)).unwrap();
+        out
+    }));
+    #[cfg(feature = "mmap")]
+    c.bench_function("write_upgrade_mapped", |b| b.iter(|| {
+        let mut out: Vec<u8> = Vec::new();
+        mkdbupgrade::write_upgrade_mapped(&mut out, black_box(path)).unwrap();
+        out
+    }));
+}
+
+/// Write many small upgrades, where any per-call setup adds up
+fn many(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let paths: Vec<String> = (0..1000)
+        .map(|n| {
+            let path = dir.path().join(format!("{n:04}.data.example.sql"));
+            std::fs::write(&path, format!("BEGIN;\nSELECT {n};\nCOMMIT;\n")).unwrap();
+            path.to_string_lossy().into_owned()
+        })
+        .collect();
+    c.bench_function("write_upgrade 1000 files", |b| b.iter(|| {
+        let mut out: Vec<u8> = Vec::new();
+        for path in &paths {
+            write_upgrade(&mut out, black_box(path)).unwrap();
+        }
+        out
+    }));
+}
+
+criterion_group!(benches, write, many);
+criterion_main!(benches);
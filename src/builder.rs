@@ -0,0 +1,584 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use crate::*;
+#[cfg(feature = "git")]
+use git2::Repository;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, Write};
+
+/// Number of upgrade files at which they are read in parallel by default
+///
+/// Starting threads costs more than it saves for a handful of files.
+/// The "build" benchmark compares the two ways of reading them. With
+/// only one thread, reading ahead just adds the cost of buffering, so
+/// the files are then always read in order.
+pub const PARALLEL_THRESHOLD: usize = 32;
+
+/// Where the prepended files are written in the upgrade script
+///
+/// ```
+/// use mkdbupgrade::PrependPosition;
+///
+/// assert_eq!(PrependPosition::default(), PrependPosition::BeforePreamble);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PrependPosition {
+    /// Before the preamble, and so before the transaction begins
+    #[default]
+    BeforePreamble,
+    /// After the preamble, inside the main transaction, before the
+    /// first upgrade
+    InsideTransaction,
+}
+
+/// Statistics about a generated upgrade script
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GenerationStats {
+    /// Version upgraded from
+    pub from_version: String,
+    /// Version upgraded to
+    pub version: String,
+    /// Number of upgrades written in the main transaction
+    pub inlined: usize,
+    /// Number of upgrades moved after the main transaction
+    pub moved: usize,
+    /// Number of files prepended to the script
+    pub prepended: usize,
+    /// Number of files appended to the script
+    pub appended: usize,
+    /// Total number of bytes written
+    pub bytes_written: u64,
+}
+
+/// Writer that counts the bytes written through it
+struct CountingWriter<'a, W: Write> {
+    inner: &'a mut W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Builder for generating a database upgrade script
+///
+/// Holds everything needed to make an upgrade script from one
+/// revision to another of an UpgradeSource. The builder methods set
+/// the optional parts, and build_to writes the finished script.
+///
+/// ```no_run
+/// # #[cfg(feature = "git")]
+/// # fn main() -> Result<(), mkdbupgrade::MkdbError> {
+/// use mkdbupgrade::*;
+/// use std::fs::File;
+///
+/// let repo = get_repository().expect("not in a git repository");
+/// let from = Revision::Branch(find_branch(&repo, "rel_3_14_0")?);
+/// let to = Revision::from(get_current_branch(&repo)?);
+/// let builder = UpgradeScriptBuilder::new(&repo, from, to)
+///     .moved(vec!["1433".to_string()])
+///     .skipped(vec!["1440".to_string()]);
+/// let (from_version, version) = builder.versions()?;
+/// let mut out = File::create(OutputOptions::default().path(&from_version, &version))?;
+/// let stats = builder.build_to(&mut out)?;
+/// println!("Wrote {} bytes", stats.bytes_written);
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "git"))]
+/// # fn main() {}
+/// ```
+pub struct UpgradeScriptBuilder<S: UpgradeSource> {
+    source: S,
+    from: S::Ref,
+    to: S::Ref,
+    from_version: Option<String>,
+    version: Option<String>,
+    moved: Vec<String>,
+    move_regex: bool,
+    strip_moved: bool,
+    allow_empty: bool,
+    skipped: Vec<String>,
+    prepend_files: Vec<String>,
+    prepend_position: PrependPosition,
+    append_files: Vec<String>,
+    options: ScriptOptions,
+    jobs: usize,
+    parallel_threshold: usize,
+    #[cfg(feature = "mmap")]
+    mmap_threshold: u64,
+}
+
+#[cfg(feature = "git")]
+impl<'repo> UpgradeScriptBuilder<GitSource<'repo>> {
+    /// Create a builder for an upgrade from one revision to another
+    ///
+    /// The upgrade files themselves are read from the repository's
+    /// working directory, so the "to" revision should be checked out.
+    pub fn new(repo: &'repo Repository, from: Revision<'repo>, to: Revision<'repo>) -> Self {
+        Self::with_source(GitSource::new(repo), from, to)
+    }
+}
+
+#[cfg(feature = "fs")]
+impl UpgradeScriptBuilder<DirSource> {
+    /// Create a builder for an upgrade between two source directories
+    ///
+    /// Each directory should be the top of an Evergreen source tree.
+    /// The upgrade files are read from the "to" directory.
+    pub fn from_dirs<P: Into<PathBuf>, Q: Into<PathBuf>>(from: P, to: Q) -> Self {
+        Self::with_source(DirSource, from.into(), to.into())
+    }
+}
+
+impl<S: UpgradeSource> UpgradeScriptBuilder<S> {
+    /// Create a builder for an upgrade between two references of a source
+    ///
+    /// ```
+    /// use mkdbupgrade::{MkdbError, UpgradeEntry, UpgradeScriptBuilder, UpgradeSource};
+    ///
+    /// // Upgrades kept in memory, with the version as the reference.
+    /// struct Canned;
+    ///
+    /// impl UpgradeSource for Canned {
+    ///     type Ref = String;
+    ///
+    ///     fn upgrades(&self, rev: &String) -> Result<Vec<UpgradeEntry>, MkdbError> {
+    ///         let mut names = vec!["1440.data.example.sql"];
+    ///         if rev == "3.14.1" {
+    ///             names.push("1441.schema.example.sql");
+    ///         }
+    ///         Ok(names.into_iter().map(|n| UpgradeEntry { path: n.to_string(), oid: String::new() }).collect())
+    ///     }
+    ///
+    ///     fn version(&self, rev: &String) -> Result<String, MkdbError> {
+    ///         Ok(rev.clone())
+    ///     }
+    ///
+    ///     fn file_path(&self, _rev: &String, path: &str) -> String {
+    ///         format!("tests/fixtures/{path}")
+    ///     }
+    /// }
+    ///
+    /// let builder = UpgradeScriptBuilder::with_source(Canned, "3.14.0".to_string(), "3.14.1".to_string());
+    /// assert_eq!(builder.upgrades()?, vec!["1441.schema.example.sql"]);
+    /// let script = builder.render_script()?;
+    /// assert!(script.starts_with("-- Upgrade script for Evergreen 3.14.0 to 3.14.1\n"));
+    /// assert!(script.contains("CREATE INDEX foo_idx ON actor.usr (id);"));
+    /// # Ok::<(), MkdbError>(())
+    /// ```
+    pub fn with_source(source: S, from: S::Ref, to: S::Ref) -> Self {
+        UpgradeScriptBuilder {
+            source,
+            from,
+            to,
+            from_version: None,
+            version: None,
+            moved: Vec::new(),
+            move_regex: false,
+            strip_moved: false,
+            allow_empty: false,
+            skipped: Vec::new(),
+            prepend_files: Vec::new(),
+            prepend_position: PrependPosition::default(),
+            append_files: Vec::new(),
+            options: ScriptOptions::default(),
+            jobs: 0,
+            parallel_threshold: PARALLEL_THRESHOLD,
+            #[cfg(feature = "mmap")]
+            mmap_threshold: MMAP_THRESHOLD,
+        }
+    }
+
+    /// Set the version being upgraded from
+    ///
+    /// Determined from the "from" revision's name if not set.
+    pub fn from_version(mut self, version: &str) -> Self {
+        self.from_version = Some(version.to_string());
+        self
+    }
+
+    /// Set the version being upgraded to
+    ///
+    /// Determined from the "to" revision's name if not set.
+    pub fn version(mut self, version: &str) -> Self {
+        self.version = Some(version.to_string());
+        self
+    }
+
+    /// Set patterns matching upgrades to move after the main transaction
+    ///
+    /// The patterns are matched literally unless move_regex is set.
+    pub fn moved(mut self, patterns: Vec<String>) -> Self {
+        self.moved = patterns;
+        self
+    }
+
+    /// Treat the moved patterns as regular expressions
+    pub fn move_regex(mut self, regex: bool) -> Self {
+        self.move_regex = regex;
+        self
+    }
+
+    /// Strip the "BEGIN;" and "COMMIT;" lines from moved upgrades too
+    ///
+    /// By default, moved upgrades are copied as they are, each with
+    /// its own transaction. When stripped, they are merged into one
+    /// transaction after the main one, unless the options turn
+    /// transactions off.
+    pub fn strip_moved(mut self, strip: bool) -> Self {
+        self.strip_moved = strip;
+        self
+    }
+
+    /// Write a script even if there are no upgrades to include
+    ///
+    /// By default, build_to returns MkdbError::NoUpgrades when there
+    /// is nothing to upgrade. With this set, it writes the script
+    /// without any upgrades instead, with everything else that would
+    /// go around them.
+    pub fn allow_empty(mut self, allow: bool) -> Self {
+        self.allow_empty = allow;
+        self
+    }
+
+    /// Set patterns matching upgrades to leave out of the script
+    pub fn skipped(mut self, patterns: Vec<String>) -> Self {
+        self.skipped = patterns;
+        self
+    }
+
+    /// Set files to write before the upgrade script proper
+    pub fn prepend_files(mut self, files: Vec<String>) -> Self {
+        self.prepend_files = files;
+        self
+    }
+
+    /// Set where the prepended files are written
+    ///
+    /// By default, they come before the preamble and its "BEGIN;".
+    /// Inside the transaction, they follow the preamble. Without a
+    /// transaction, that is simply after the preamble.
+    pub fn prepend_position(mut self, position: PrependPosition) -> Self {
+        self.prepend_position = position;
+        self
+    }
+
+    /// Set files to write after the upgrade script proper
+    pub fn append_files(mut self, files: Vec<String>) -> Self {
+        self.append_files = files;
+        self
+    }
+
+    /// Set the options for the text added around the upgrades
+    ///
+    /// By default, the upgrades are wrapped in a transaction and the
+    /// auditor block is added.
+    pub fn options(mut self, options: ScriptOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Set how many upgrade files may be read at the same time
+    ///
+    /// 0, the default, allows one per CPU. 1 reads the files one at a
+    /// time, in order, without starting any threads.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Set the number of upgrade files at which they are read in parallel
+    ///
+    /// Fewer files than this are read one at a time, in order.
+    /// Defaults to PARALLEL_THRESHOLD.
+    pub fn parallel_threshold(mut self, files: usize) -> Self {
+        self.parallel_threshold = files;
+        self
+    }
+
+    /// Set the size, in bytes, at which upgrade files are memory-mapped
+    ///
+    /// Upgrades smaller than this are read a line at a time. Defaults
+    /// to MMAP_THRESHOLD. Moved upgrades are copied as they are unless
+    /// strip_moved is set, in which case they are mapped too.
+    #[cfg(feature = "mmap")]
+    pub fn mmap_threshold(mut self, bytes: u64) -> Self {
+        self.mmap_threshold = bytes;
+        self
+    }
+
+    /// Get the "from" and "to" versions
+    ///
+    /// Returns the versions that were set or those determined by the
+    /// source. Returns an error if either cannot be determined.
+    pub fn versions(&self) -> Result<(String, String), MkdbError> {
+        let from_version = match &self.from_version {
+            Some(v) => v.clone(),
+            None => self.source.version(&self.from)?,
+        };
+        let version = match &self.version {
+            Some(v) => v.clone(),
+            None => self.source.version(&self.to)?,
+        };
+        Ok((from_version, version))
+    }
+
+    /// Get the upgrades that will be included in the script
+    ///
+    /// Returns the new upgrades in the "to" revision, minus any that
+    /// are skipped, in the order that they are found. Moved upgrades
+    /// are included.
+    pub fn upgrades(&self) -> Result<Vec<String>, MkdbError> {
+        // Check the patterns before listing anything.
+        let skipped = build_move_matcher(&self.skipped)?;
+        let upgrades = self.source.new_upgrades(&self.from, &self.to)?;
+        Ok(upgrades.into_iter().filter(|file| !skipped.matches(file)).collect())
+    }
+
+    /// Check that the prepended and appended files can be read
+    ///
+    /// Each file is opened, and it must not be a directory. Returns
+    /// MkdbError::Files with an error for every file that fails, so
+    /// that they can all be reported at once.
+    pub fn check_files(&self) -> Result<(), MkdbError> {
+        let errors: Vec<MkdbError> = self.prepend_files.iter()
+            .chain(&self.append_files)
+            .filter_map(|file| check_file(file).err().map(|e| file_error(file, e)))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(MkdbError::Files(errors))
+        }
+    }
+
+    /// Generate the upgrade script and write it to the output
+    ///
+    /// Returns statistics about the script on success. Returns
+    /// MkdbError::NoUpgrades without writing anything if there are no
+    /// upgrades to include, unless allow_empty is set. Returns any
+    /// other error as it occurs, in which case the output may be
+    /// incomplete.
+    ///
+    /// The versions, patterns, and prepended and appended files are
+    /// checked before the upgrades are listed, so that a mistake in
+    /// them is reported without walking either revision.
+    pub fn build_to<W: Write>(&self, out: &mut W) -> Result<GenerationStats, MkdbError> {
+        let (from_version, version) = self.versions()?;
+        let moved_matcher = if self.move_regex {
+            build_move_matcher(&self.moved)?
+        } else {
+            build_literal_matcher(&self.moved)
+        };
+        self.check_files()?;
+        let upgrades = self.upgrades()?;
+        if upgrades.is_empty() && !self.allow_empty {
+            return Err(MkdbError::NoUpgrades);
+        }
+        let out = &mut CountingWriter { inner: out, count: 0 };
+
+        if self.prepend_position == PrependPosition::BeforePreamble {
+            self.write_prepended(out)?;
+        }
+
+        // Write our preamble.
+        write_preamble(out, &from_version, &version, &self.options)?;
+
+        if self.prepend_position == PrependPosition::InsideTransaction {
+            self.write_prepended(out)?;
+        }
+
+        // Set aside the upgrades that need to be moved.
+        let (inline, moved) = moved_matcher.partition(upgrades);
+        let files = self.read_upgrades(&inline, &moved)?;
+        let (inline_files, moved_files) = files.split_at(inline.len());
+        write_upgrades(out, inline_files)?;
+        if self.options.transaction {
+            writeln!(out, "COMMIT;\n")?;
+        }
+        if !moved.is_empty() {
+            let merged = self.strip_moved && self.options.transaction;
+            writeln!(out, "-- Start of moved upgrades")?;
+            if merged {
+                writeln!(out, "BEGIN;")?;
+            }
+            write_upgrades(out, moved_files)?;
+            if merged {
+                writeln!(out, "COMMIT;")?;
+            }
+            writeln!(out, "-- End of moved upgrades\n")?;
+        }
+
+        // Write code to update the auditor tables
+        write_auditor_block(out, &self.options)?;
+
+        if !self.append_files.is_empty() {
+            writeln!(out, "\n-- Start of appended code")?;
+            for file in &self.append_files {
+                write_file(out, file).map_err(|e| file_error(file, e))?;
+            }
+            writeln!(out, "-- End of appended code")?;
+        }
+        Ok(GenerationStats {
+            from_version,
+            version,
+            inlined: inline.len(),
+            moved: moved.len(),
+            prepended: self.prepend_files.len(),
+            appended: self.append_files.len(),
+            bytes_written: out.count,
+        })
+    }
+
+    /// Write the prepended files between their markers, if there are any
+    fn write_prepended<W: Write>(&self, out: &mut W) -> Result<(), MkdbError> {
+        if !self.prepend_files.is_empty() {
+            writeln!(out, "-- Start of prepended code")?;
+            for file in &self.prepend_files {
+                write_file(out, file).map_err(|e| file_error(file, e))?;
+            }
+            writeln!(out, "-- End of prepended code\n")?;
+        }
+        Ok(())
+    }
+
+    /// Find the inline and moved upgrade files, reading them if needed
+    ///
+    /// The inline files come first in the result, followed by the moved
+    /// files. With parallel_threshold or more files in all, both kinds
+    /// are read ahead in one pass on up to jobs threads. Otherwise, and
+    /// for large files, nothing is read until the file is written.
+    fn read_upgrades(&self, inline: &[String], moved: &[String]) -> Result<Vec<UpgradeFile>, MkdbError> {
+        let mut files: Vec<UpgradeFile> = inline
+            .iter()
+            .map(|f| (f, true))
+            .chain(moved.iter().map(|f| (f, self.strip_moved)))
+            .map(|(f, strip)| {
+                let path = self.source.file_path(&self.to, f);
+                let large = self.is_large(&path);
+                UpgradeFile { path, strip, large, body: None }
+            })
+            .collect();
+        let threads = if self.jobs == 0 { rayon::current_num_threads() } else { self.jobs };
+        if threads == 1 || files.len() < self.parallel_threshold {
+            return Ok(files);
+        }
+        let mut read = || {
+            files.par_iter_mut().filter(|file| !file.large).try_for_each(|file| {
+                let mut body: Vec<u8> = Vec::new();
+                copy_upgrade(&mut body, &file.path, file.strip, false).map_err(|e| file_error(&file.path, e))?;
+                file.body = Some(body);
+                Ok::<(), MkdbError>(())
+            })
+        };
+        // The global pool already has a thread per CPU.
+        if self.jobs == 0 {
+            read()?;
+        } else {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(self.jobs).build().map_err(io::Error::other)?;
+            pool.install(read)?;
+        }
+        Ok(files)
+    }
+
+    /// Check whether an upgrade file is big enough to memory-map
+    #[cfg(feature = "mmap")]
+    fn is_large(&self, path: &str) -> bool {
+        std::fs::metadata(path).is_ok_and(|m| m.len() >= self.mmap_threshold)
+    }
+
+    /// Check whether an upgrade file is big enough to memory-map
+    #[cfg(not(feature = "mmap"))]
+    fn is_large(&self, _path: &str) -> bool {
+        false
+    }
+
+    /// Generate the upgrade script as a string
+    ///
+    /// Works like build_to, but returns the whole script in memory
+    /// instead of writing it anywhere.
+    pub fn render_script(&self) -> Result<String, MkdbError> {
+        let mut out: Vec<u8> = Vec::new();
+        self.build_to(&mut out)?;
+        String::from_utf8(out).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+    }
+}
+
+/// An upgrade file to be copied to the script
+struct UpgradeFile {
+    /// Path to read the file from
+    path: String,
+    /// Whether to strip the file's "BEGIN;" and "COMMIT;" lines
+    strip: bool,
+    /// Whether the file is big enough to memory-map
+    large: bool,
+    /// The file's contents, as they will be written, if read ahead
+    body: Option<Vec<u8>>,
+}
+
+/// Copy upgrade files to the output in order
+///
+/// Files that were read ahead are written from memory, and the rest
+/// are copied from disk.
+fn write_upgrades<W: Write>(out: &mut W, files: &[UpgradeFile]) -> Result<(), MkdbError> {
+    for file in files {
+        match &file.body {
+            Some(body) => out.write_all(body)?,
+            None => copy_upgrade(out, &file.path, file.strip, file.large).map_err(|e| file_error(&file.path, e))?,
+        }
+    }
+    Ok(())
+}
+
+/// Copy one upgrade file, stripping its transaction if strip is set
+///
+/// A large file is memory-mapped when it is stripped, if the mmap
+/// feature is enabled.
+fn copy_upgrade<W: Write>(out: &mut W, path: &str, strip: bool, large: bool) -> io::Result<()> {
+    match (strip, large) {
+        #[cfg(feature = "mmap")]
+        (true, true) => write_upgrade_mapped(out, path),
+        (true, _) => write_upgrade(out, path),
+        (false, _) => write_file(out, path),
+    }
+}
+
+/// Check that a file can be opened for reading and is not a directory
+fn check_file(path: &str) -> io::Result<()> {
+    if File::open(path)?.metadata()?.is_dir() {
+        return Err(io::Error::from(io::ErrorKind::IsADirectory));
+    }
+    Ok(())
+}
+
+/// Attach the name of the file being copied to an I/O error
+fn file_error(path: &str, source: io::Error) -> MkdbError {
+    MkdbError::File { path: path.to_string(), source }
+}
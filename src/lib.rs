@@ -17,50 +17,149 @@
  * You should have received a copy of the GNU General Public License
  * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
  */
-use git2::{Branch, BranchType, ObjectType, Repository, TreeWalkMode, TreeWalkResult};
+#[cfg(feature = "git")]
+use git2::{Branch, BranchType, Commit, ObjectType, Oid, Reference, Repository, Tree, TreeWalkMode, TreeWalkResult};
 use regex::Regex;
-use std::env::var;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::env::{VarError, var};
 use std::error::Error;
 use std::fmt;
-use std::fs::{File, read_to_string};
-use std::io::{self, Write};
-use std::path::Path;
-use std::process::Command;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::LazyLock;
+#[cfg(feature = "git")]
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+#[cfg(not(any(feature = "git", feature = "fs")))]
+compile_error!("mkdbupgrade requires the git feature, the fs feature, or both");
+
+mod builder;
+mod source;
+
+pub use builder::{GenerationStats, PARALLEL_THRESHOLD, PrependPosition, UpgradeScriptBuilder};
+#[cfg(feature = "fs")]
+pub use source::DirSource;
+#[cfg(feature = "git")]
+pub use source::GitSource;
+pub use source::UpgradeSource;
+
+/// Path of the database upgrade directory in an Evergreen repository
+pub const UPGRADE_DIR: &str = "Open-ILS/src/sql/Pg/upgrade";
+
+/// Path of the version upgrade script directory in an Evergreen repository
+pub const VERSION_UPGRADE_DIR: &str = "Open-ILS/src/sql/Pg/version-upgrade";
 
 /// Error returned if current repository head reference is not a branch
 #[derive(Debug, Clone)]
-pub struct HeadError;
+pub struct HeadError {
+    /// What HEAD points at: an abbreviated commit id if detached,
+    /// otherwise the name of the reference
+    pub target: String,
+    /// Whether HEAD is detached
+    pub detached: bool,
+}
 
 impl fmt::Display for HeadError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "head is not a branch")
+        if self.detached {
+            write!(f, "HEAD is detached at {}, not a branch", self.target)
+        } else {
+            write!(f, "HEAD is {}, not a branch", self.target)
+        }
     }
 }
 
 impl Error for HeadError {}
 
+/// Errors returned by the functions in this crate
+#[derive(Debug, Error)]
+pub enum MkdbError {
+    /// An error from git
+    #[cfg(feature = "git")]
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+    /// An error reading or writing a file or running a program
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// A version could not be determined or parsed
+    #[error("unable to determine version from {0}")]
+    Version(String),
+    /// The current repository head reference is not a branch
+    #[error(transparent)]
+    Head(#[from] HeadError),
+    /// An error reading an input file or copying it to the output
+    #[error("{path}: {source}")]
+    File {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Input files that cannot be read, each with its MkdbError::File
+    #[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<String>>().join("; "))]
+    Files(Vec<MkdbError>),
+    /// There were no upgrades to put in the script
+    #[error("no upgrades were found")]
+    NoUpgrades,
+    /// A moved or skipped pattern is not a valid regular expression
+    #[error("invalid pattern: {0}")]
+    Pattern(#[from] regex::Error),
+    /// The GIT_EDITOR, VISUAL, or EDITOR environment variable could
+    /// not be read
+    #[error("GIT_EDITOR, VISUAL, or EDITOR: {0}")]
+    Editor(#[from] VarError),
+    /// An upgrade script did not apply cleanly in a test run
+    #[error("test run failed, psql exited with {status}:\n{errors}")]
+    TestRun {
+        status: ExitStatus,
+        errors: String,
+    },
+    /// The PAGER environment variable could not be read
+    #[error("PAGER: {0}")]
+    Pager(VarError),
+    /// A program that was run, such as the editor, exited with a
+    /// failure status
+    #[error("{program} exited with {status}")]
+    Status {
+        program: String,
+        status: ExitStatus,
+    },
+}
+
 /// Get reference to current git repository
 ///
 /// Returns None if current directory is not a repository
+#[cfg(feature = "git")]
 pub fn get_repository() -> Option<Repository> {
-    match Repository::open("./") {
-        Ok(r) => Some(r),
-        Err(_) => None,
-    }
+    Repository::open("./").ok()
 }
 
 /// Get the current git branch in repository
 ///
 /// Returns current branch in the repository or an error
-pub fn get_current_branch(repo: &Repository) -> Result<Branch<'_>, Box<dyn Error>> {
-    let head = match repo.head() {
-        Ok(h) => h,
-        Err(e) => return Err(Box::new(e)),
-    };
+#[cfg(feature = "git")]
+pub fn get_current_branch(repo: &Repository) -> Result<Branch<'_>, MkdbError> {
+    let head = repo.head()?;
     if head.is_branch() {
         Ok(Branch::wrap(head))
+    } else if repo.head_detached()? {
+        let short_id = head.peel_to_commit()?.as_object().short_id()?;
+        Err(HeadError {
+            target: short_id.as_str().unwrap_or_default().to_string(),
+            detached: true,
+        }.into())
     } else {
-        Err(HeadError.into())
+        Err(HeadError {
+            target: head.name().unwrap_or("an unknown reference").to_string(),
+            detached: false,
+        }.into())
     }
 }
 
@@ -68,18 +167,178 @@ pub fn get_current_branch(repo: &Repository) -> Result<Branch<'_>, Box<dyn Error
 ///
 /// Searches for local and remote branches. Returns the branch object
 /// if found.
-pub fn find_branch<'a>(repo: &'a Repository, name: &String) -> Result<Branch<'a>, Box<dyn Error>> {
+#[cfg(feature = "git")]
+pub fn find_branch<'a>(repo: &'a Repository, name: &str) -> Result<Branch<'a>, MkdbError> {
     match repo.find_branch(name, BranchType::Local) {
         Ok(b) => Ok(b),
-        Err(_) => {
-            match repo.find_branch(name, BranchType::Remote) {
-                Ok(r) => Ok(r),
-                Err(e) => Err(Box::new(e)),
+        Err(_) => Ok(repo.find_branch(name, BranchType::Remote)?),
+    }
+}
+
+
+/// Find named tag in the repository
+///
+/// Returns the tag's reference if found.
+#[cfg(feature = "git")]
+pub fn find_tag<'a>(repo: &'a Repository, name: &str) -> Result<Reference<'a>, MkdbError> {
+    Ok(repo.find_reference(&format!("refs/tags/{}", name))?)
+}
+
+/// Find a commit in the repository by its (possibly abbreviated) id
+///
+/// Returns the commit object if found.
+#[cfg(feature = "git")]
+pub fn find_commit<'a>(repo: &'a Repository, id: &str) -> Result<Commit<'a>, MkdbError> {
+    Ok(repo.revparse_single(id)?.peel_to_commit()?)
+}
+
+/// A git revision from which upgrades are read
+///
+/// Branches and tags have names from which a version may be
+/// determined. Commits do not.
+#[cfg(feature = "git")]
+pub enum Revision<'repo> {
+    /// A local or remote branch
+    Branch(Branch<'repo>),
+    /// A tag reference
+    Tag(Reference<'repo>),
+    /// A commit found by its id
+    Commit(Commit<'repo>),
+}
+
+#[cfg(feature = "git")]
+impl<'repo> Revision<'repo> {
+    /// Get the name of the branch or tag
+    ///
+    /// Returns None for a commit or a name that is not valid UTF-8.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Revision::Branch(b) => b.name().ok().flatten(),
+            Revision::Tag(r) => r.shorthand(),
+            Revision::Commit(_) => None,
+        }
+    }
+
+    /// Get the commit at this revision
+    pub fn commit(&self) -> Result<Commit<'repo>, git2::Error> {
+        match self {
+            Revision::Branch(b) => b.get().peel_to_commit(),
+            Revision::Tag(r) => r.peel_to_commit(),
+            Revision::Commit(c) => Ok(c.clone()),
+        }
+    }
+
+    /// Get the tree of files at this revision
+    pub fn tree(&self) -> Result<Tree<'repo>, git2::Error> {
+        match self {
+            Revision::Branch(b) => b.get().peel_to_tree(),
+            Revision::Tag(r) => r.peel_to_tree(),
+            Revision::Commit(c) => c.tree(),
+        }
+    }
+}
+
+#[cfg(feature = "git")]
+impl<'repo> From<Branch<'repo>> for Revision<'repo> {
+    fn from(branch: Branch<'repo>) -> Self {
+        Revision::Branch(branch)
+    }
+}
+
+/// Matches the _X_Y_Z version in a branch or tag name
+static NAME_VERSION_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"_(\d{1,2})_(\d{1,2})_(\d{1,2})").unwrap());
+
+/// Matches the "BEGIN;" and "COMMIT;" lines of an upgrade
+static TRANSACTION_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*(?:BEGIN|COMMIT);").unwrap());
+
+/// Matches any statement that begins (group 1) or ends (group 2) a
+/// transaction, in any case and with any of PostgreSQL's noise words
+static TRANSACTION_STATEMENT_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(r"(?i)^\s*(BEGIN|START\s+TRANSACTION)\b[^;]*;",
+                       r"|^\s*(COMMIT|END|ROLLBACK|ABORT)(?:\s+(?:WORK|TRANSACTION))?(?:\s+AND\s+(?:NO\s+)?CHAIN)?\s*;"))
+        .unwrap()
+});
+
+/// Matches a dollar quote, such as $$ or $body$, that starts or ends
+/// a string such as a function body
+static DOLLAR_QUOTE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$(?:[A-Za-z_][A-Za-z_0-9]*)?\$").unwrap());
+
+/// Tracks whether the lines of a script are inside a dollar-quoted string
+///
+/// A function body's "END;" is not the end of a transaction, so
+/// transaction statements are only looked for outside of these.
+#[derive(Default)]
+struct DollarQuotes {
+    open: Option<String>,
+}
+
+impl DollarQuotes {
+    /// Find the transaction statement that a line begins with, if any
+    ///
+    /// Returns None for a line that starts inside a dollar-quoted
+    /// string. Call this for every line in order.
+    fn statement<'l>(&mut self, line: &'l str) -> Option<regex::Captures<'l>> {
+        let quoted = self.open.is_some();
+        for quote in DOLLAR_QUOTE_REGEX.find_iter(line) {
+            match &self.open {
+                None => self.open = Some(quote.as_str().to_string()),
+                Some(tag) if tag == quote.as_str() => self.open = None,
+                Some(_) => (),
             }
-        },
+        }
+        if quoted { None } else { TRANSACTION_STATEMENT_REGEX.captures(line) }
     }
 }
 
+/// Get the "version" from a name
+///
+/// Looks for a string like _X_Y_Z (where X, Y, an Z are 1 or two-digit
+/// numbers) in the name passed as an argument.
+pub(crate) fn get_name_version(name: &str) -> Option<String> {
+    // Assumes a name like rel_X_Y_Z.
+    let (_, [x, y, z]) = NAME_VERSION_REGEX.captures(name).map(|caps| caps.extract())?;
+    Some(format!("{}.{}.{}", x, y, z))
+}
+
+/// Normalize an Evergreen version to the X.Y.Z form
+///
+/// Accepts a version like X.Y.Z, X_Y_Z, or rel_X_Y_Z, where X, Y, and
+/// Z are 1 or 2-digit numbers. A version that is already in the X.Y.Z
+/// form is returned without copying it.
+///
+/// Returns None if the version is not in any of these forms.
+///
+/// ```
+/// use mkdbupgrade::normalize_version;
+/// use std::borrow::Cow;
+///
+/// assert!(matches!(normalize_version("3.14.1"), Some(Cow::Borrowed("3.14.1"))));
+/// assert_eq!(normalize_version("rel_3_14_1").as_deref(), Some("3.14.1"));
+/// assert_eq!(normalize_version("3.14"), None);
+/// ```
+pub fn normalize_version(version: &str) -> Option<Cow<'_, str>> {
+    if is_version(version, '.') {
+        return Some(Cow::Borrowed(version));
+    }
+    let version = version.strip_prefix("rel_").unwrap_or(version);
+    if is_version(version, '_') {
+        Some(Cow::Owned(version.replace('_', ".")))
+    } else {
+        None
+    }
+}
+
+/// Check for three 1 or 2-digit numbers separated by sep
+fn is_version(version: &str, sep: char) -> bool {
+    let mut count = 0;
+    for part in version.split(sep) {
+        count += 1;
+        if count > 3 || !(1..=2).contains(&part.len()) || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return false;
+        }
+    }
+    count == 3
+}
 
 /// Get the "version" from a git branch name
 ///
@@ -88,130 +347,1067 @@ pub fn find_branch<'a>(repo: &'a Repository, name: &String) -> Result<Branch<'a>
 ///
 /// If the pattern is matched, returns an Option with a string value
 /// of X.Y.Z. If not, None is returned.
+///
+/// ```no_run
+/// use mkdbupgrade::{MkdbError, get_branch_version, get_current_branch, get_repository};
+///
+/// let repo = get_repository().expect("not in a git repository");
+/// let branch = get_current_branch(&repo)?;
+/// match get_branch_version(&branch) {
+///     Some(version) => println!("On Evergreen {version}"),
+///     None => println!("Not on a release branch"),
+/// }
+/// # Ok::<(), MkdbError>(())
+/// ```
+#[cfg(feature = "git")]
 pub fn get_branch_version(branch: &Branch) -> Option<String> {
-    // Assumes a branch named like rel_X_Y_Z.
-    let regex = Regex::new(r"_(\d{1,2})_(\d{1,2})_(\d{1,2})").unwrap();
-    let branch_name = match branch.name() {
-        Ok(Some(s)) => s,
-        Ok(None) => return None,
-        Err(_) => return None,
-    };
-    let Some((_, [x, y, z])) =
-        regex.captures(branch_name).map(|caps| caps.extract()) else { return None };
-    Some(format!("{}.{}.{}", x, y, z))
+    get_name_version(branch.name().ok()??)
 }
 
-/// Get a list of Evergreen database upgrade files from a given branch
-fn get_branch_upgrades(repo: &Repository, branch: &Branch) -> Result<Vec<String>, Box<dyn Error>> {
-    let mut upgrades: Vec<String> = Vec::new();
-    let dirpath = "Open-ILS/src/sql/Pg/upgrade";
-    let tree = branch.get().peel_to_tree()?;
-    match tree.get_path(Path::new(dirpath)) {
-        Ok(tree_entry) => {
-            if let Some(ObjectType::Tree) = tree_entry.kind() {
-                let object = tree_entry.to_object(&repo)?;
-                let dir_tree = object.as_tree().unwrap();
-                dir_tree.walk(TreeWalkMode::PreOrder, |_, entry| {
-                    match entry.name() {
-                        Some(n) => upgrades.push(format!("{}/{}", dirpath, n)),
-                        None => (),
-                    }
-                    TreeWalkResult::Ok
-                })?;
+/// Get the "version" from the name of a git revision
+///
+/// Works like get_branch_version for branches and tags. Always
+/// returns None for commits, since they have no name.
+#[cfg(feature = "git")]
+pub fn get_revision_version(rev: &Revision) -> Option<String> {
+    get_name_version(rev.name()?)
+}
+
+/// An Evergreen database upgrade file in a branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UpgradeEntry {
+    /// Path of the upgrade file relative to the repository root
+    pub path: String,
+    /// Git object id of the upgrade file's contents, in hexadecimal
+    pub oid: String,
+}
+
+/// Differences between the upgrades in two branches
+///
+/// Each vector holds the paths of the upgrade files in tree order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UpgradeDiff {
+    /// Upgrades in the "to" branch that are not in the "from" branch
+    pub added: Vec<String>,
+    /// Upgrades in the "from" branch that are not in the "to" branch
+    pub removed: Vec<String>,
+    /// Upgrades in both branches whose contents differ
+    pub changed: Vec<String>,
+}
+
+/// Get a list of Evergreen database upgrade files from a given revision
+///
+/// Returns an UpgradeEntry for each file in the upgrade directory of
+/// the revision, including any in its subdirectories, in tree order,
+/// on success. Returns the error on failure.
+///
+/// The listing is cached for the repository until clear_upgrade_cache
+/// is called, so listing the same upgrade directory again does not
+/// walk its tree.
+#[cfg(feature = "git")]
+pub fn get_branch_upgrades(repo: &Repository, rev: &Revision) -> Result<Vec<UpgradeEntry>, MkdbError> {
+    let mut upgrades: Vec<UpgradeEntry> = Vec::new();
+    let dirpath = UPGRADE_DIR;
+    if let Some(id) = upgrade_tree_id(rev)? {
+        let key = cache_key(repo, id);
+        if let Some(cached) = upgrade_cache().get(&key) {
+            return Ok(cached.clone());
+        }
+        let dir_tree = repo.find_tree(id)?;
+        // The root is the path of the entry's directory within the
+        // upgrade directory, ending with a slash unless it is empty.
+        dir_tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+            if let (Some(ObjectType::Blob), Some(n)) = (entry.kind(), entry.name()) {
+                upgrades.push(UpgradeEntry {
+                    path: format!("{}/{}{}", dirpath, root, n),
+                    oid: entry.id().to_string(),
+                });
             }
-        },
-        Err(e) => return Err(Box::new(e)),
+            TreeWalkResult::Ok
+        })?;
+        upgrade_cache().insert(key, upgrades.clone());
     }
     Ok(upgrades)
 }
 
+/// Get the id of the upgrade directory's tree in a revision
+///
+/// Only the trees on the way to the upgrade directory are read, not
+/// the rest of the revision's files. Returns None if the upgrade path
+/// is not a directory.
+#[cfg(feature = "git")]
+fn upgrade_tree_id(rev: &Revision) -> Result<Option<Oid>, MkdbError> {
+    let entry = rev.tree()?.get_path(Path::new(UPGRADE_DIR))?;
+    Ok((entry.kind() == Some(ObjectType::Tree)).then(|| entry.id()))
+}
+
+/// Key of an upgrade directory's listing in the cache
+///
+/// The path of the repository's git directory and the directory's
+/// tree id.
+#[cfg(feature = "git")]
+type CacheKey = (PathBuf, Oid);
+
+/// Listings of upgrade directories, keyed by repository and tree id
+///
+/// A tree id names the tree's contents, so its listing never goes
+/// stale as branches move. Keeping each repository's listings apart
+/// means that one is only reused where its objects can be read.
+#[cfg(feature = "git")]
+static UPGRADE_CACHE: LazyLock<Mutex<HashMap<CacheKey, Vec<UpgradeEntry>>>> = LazyLock::new(Default::default);
+
+/// Make the cache key for an upgrade directory in a repository
+#[cfg(feature = "git")]
+fn cache_key(repo: &Repository, id: Oid) -> CacheKey {
+    (repo.path().to_path_buf(), id)
+}
+
+/// Lock the upgrade cache
+#[cfg(feature = "git")]
+fn upgrade_cache() -> MutexGuard<'static, HashMap<CacheKey, Vec<UpgradeEntry>>> {
+    // The cache is always left in a usable state, even by a panic.
+    UPGRADE_CACHE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Forget the upgrade listings saved by get_branch_upgrades
+///
+/// Each upgrade directory is only walked once, and the listing is
+/// reused when the same directory is found again, as when comparing
+/// one branch against several others. Clear the cache to free the
+/// memory when you are done.
+#[cfg(feature = "git")]
+pub fn clear_upgrade_cache() {
+    upgrade_cache().clear();
+}
+
 /// Get the list of ugprades needed to upgrade from "from" to "to" branches
 ///
-/// Uses the private get_branch_upgrades function.
+/// Compares the upgrade directories of the two branches. Where they
+/// share a subdirectory, or the whole directory, it is skipped
+/// without being walked.
+///
+/// Returns a vector of Strings with the paths of the upgrade files in
+/// the "to" branch that do not exist in the "from" branch on success.
+/// Returns the error on failure.
 ///
-/// Returns a vector of Strings with the upgrades in the "to" branch
-/// that do not exist in the "from" branch on success. Returns the
-/// error on failure.
-pub fn get_upgrades(repo: &Repository, from: &Branch, to: &Branch) -> Result<Vec<String>, Box<dyn Error>> {
-    let from_upgrades: Vec<String> = get_branch_upgrades(repo, from)?;
-    let to_upgrades: Vec<String> = get_branch_upgrades(repo, to)?;
-    let upgrades: Vec<String> = to_upgrades.into_iter().filter(|item| !from_upgrades.contains(item)).collect();
+/// ```no_run
+/// use mkdbupgrade::{MkdbError, Revision, find_branch, find_tag, get_current_branch, get_repository, get_upgrades};
+///
+/// let repo = get_repository().expect("not in a git repository");
+/// let from = Revision::Tag(find_tag(&repo, "rel_3_14_0")?);
+/// let to = Revision::from(get_current_branch(&repo)?);
+/// for path in get_upgrades(&repo, &from, &to)? {
+///     println!("{path}");
+/// }
+///
+/// // Any two revisions may be compared.
+/// let from = Revision::Branch(find_branch(&repo, "origin/rel_3_14")?);
+/// println!("{} new upgrades", get_upgrades(&repo, &from, &to)?.len());
+/// # Ok::<(), MkdbError>(())
+/// ```
+#[cfg(feature = "git")]
+pub fn get_upgrades(repo: &Repository, from: &Revision, to: &Revision) -> Result<Vec<String>, MkdbError> {
+    let mut upgrades: Vec<String> = Vec::new();
+    let Some(to_id) = upgrade_tree_id(to)? else {
+        return Ok(upgrades);
+    };
+    let from_id = upgrade_tree_id(from)?;
+    if from_id == Some(to_id) {
+        return Ok(upgrades);
+    }
+    // Listings that were already made are quicker to compare.
+    if let Some(from_id) = from_id {
+        let cache = upgrade_cache();
+        let (from_key, to_key) = (cache_key(repo, from_id), cache_key(repo, to_id));
+        if let (Some(from_upgrades), Some(to_upgrades)) = (cache.get(&from_key), cache.get(&to_key)) {
+            return Ok(new_upgrades(from_upgrades, to_upgrades));
+        }
+    }
+    let from_tree = from_id.map(|id| repo.find_tree(id)).transpose()?;
+    add_new_upgrades(repo, from_tree.as_ref(), &repo.find_tree(to_id)?, UPGRADE_DIR, &mut upgrades)?;
     Ok(upgrades)
 }
 
-/// Read a file and write its contents to the output file
+/// Add the paths of files in one tree that are not in another
+///
+/// Compares the "to" tree with the "from" tree, if any, and adds the
+/// path of each file that is only in "to" to upgrades, in tree order.
+/// A subtree with the same id in both has the same files, so it is
+/// skipped without being read. The trees are found at dirpath.
+#[cfg(feature = "git")]
+fn add_new_upgrades(repo: &Repository, from: Option<&Tree>, to: &Tree, dirpath: &str, upgrades: &mut Vec<String>) -> Result<(), git2::Error> {
+    for entry in to.iter() {
+        let Some(name) = entry.name() else { continue };
+        let old = from.and_then(|t| t.get_name(name));
+        let path = format!("{}/{}", dirpath, name);
+        if entry.kind() == Some(ObjectType::Tree) {
+            let old_tree = match old {
+                Some(o) if o.id() == entry.id() => continue,
+                Some(o) if o.kind() == Some(ObjectType::Tree) => Some(repo.find_tree(o.id())?),
+                _ => None,
+            };
+            add_new_upgrades(repo, old_tree.as_ref(), &repo.find_tree(entry.id())?, &path, upgrades)?;
+        } else if old.is_none() {
+            upgrades.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Compare the upgrades in the "from" and "to" branches
 ///
-/// Read a file (inf) and write its entire contents to the output file
-/// handle (outf).
+/// Uses the get_branch_upgrades function.
+///
+/// Returns an UpgradeDiff listing the upgrades added to, removed
+/// from, and changed in the "to" branch on success. Returns the error
+/// on failure.
+#[cfg(feature = "git")]
+pub fn get_upgrade_diff(repo: &Repository, from: &Revision, to: &Revision) -> Result<UpgradeDiff, MkdbError> {
+    let from_upgrades: Vec<UpgradeEntry> = get_branch_upgrades(repo, from)?;
+    let to_upgrades: Vec<UpgradeEntry> = get_branch_upgrades(repo, to)?;
+    Ok(diff_upgrades(&from_upgrades, &to_upgrades))
+}
+
+/// Get the paths of upgrades in "to" that are not in "from"
+///
+/// Returns the paths in the order that they appear in "to".
+pub fn new_upgrades(from: &[UpgradeEntry], to: &[UpgradeEntry]) -> Vec<String> {
+    let from_upgrades: HashSet<&str> = from.iter().map(|e| e.path.as_str()).collect();
+    to.iter().filter(|item| !from_upgrades.contains(item.path.as_str())).map(|e| e.path.clone()).collect()
+}
+
+/// Compare two lists of upgrades
+///
+/// Returns an UpgradeDiff listing the upgrades added to, removed
+/// from, and changed in "to" relative to "from".
+pub fn diff_upgrades(from_upgrades: &[UpgradeEntry], to_upgrades: &[UpgradeEntry]) -> UpgradeDiff {
+    let mut diff = UpgradeDiff::default();
+    let from_oids: HashMap<&str, &str> = from_upgrades.iter().map(|e| (e.path.as_str(), e.oid.as_str())).collect();
+    let to_paths: HashSet<&str> = to_upgrades.iter().map(|e| e.path.as_str()).collect();
+    for entry in to_upgrades {
+        match from_oids.get(entry.path.as_str()) {
+            Some(&oid) if oid != entry.oid => diff.changed.push(entry.path.clone()),
+            Some(_) => (),
+            None => diff.added.push(entry.path.clone()),
+        }
+    }
+    for entry in from_upgrades {
+        if !to_paths.contains(entry.path.as_str()) {
+            diff.removed.push(entry.path.clone());
+        }
+    }
+    diff
+}
+
+/// Get a list of Evergreen database upgrade files from a directory
+///
+/// Reads the upgrade directory under root, which should be the top of
+/// an Evergreen source tree, without using git. The entries have the
+/// same paths and object ids that get_branch_upgrades would return
+/// for the same files in git.
+///
+/// Returns an UpgradeEntry for each file, including any in
+/// subdirectories, sorted by path on success. Returns the error on
+/// failure.
+#[cfg(feature = "fs")]
+pub fn get_dir_upgrades<P: AsRef<Path>>(root: P) -> Result<Vec<UpgradeEntry>, MkdbError> {
+    let mut upgrades: Vec<UpgradeEntry> = Vec::new();
+    add_dir_upgrades(&root.as_ref().join(UPGRADE_DIR), UPGRADE_DIR, &mut upgrades)?;
+    // Sorting the whole paths puts them in the same order as git's
+    // tree order.
+    upgrades.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(upgrades)
+}
+
+/// Add an UpgradeEntry for each file under a directory
+///
+/// The entries' paths start with dirpath, the directory's path
+/// relative to the top of the source tree.
+#[cfg(feature = "fs")]
+fn add_dir_upgrades(dir: &Path, dirpath: &str, upgrades: &mut Vec<UpgradeEntry>) -> Result<(), MkdbError> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = format!("{}/{}", dirpath, entry.file_name().to_string_lossy());
+        if file_type.is_dir() {
+            add_dir_upgrades(&entry.path(), &path, upgrades)?;
+        } else if file_type.is_file() {
+            let content = std::fs::read(entry.path())?;
+            upgrades.push(UpgradeEntry { path, oid: blob_id(&content) });
+        }
+    }
+    Ok(())
+}
+
+/// Calculate the git blob object id of some content
+#[cfg(feature = "fs")]
+fn blob_id(content: &[u8]) -> String {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(format!("blob {}\0", content.len()).as_bytes());
+    hasher.update(content);
+    hasher.digest().to_string()
+}
+
+/// Read a file and write its contents to the output
+///
+/// Read a file (inf) and write its entire contents to the output
+/// writer (out). If the file does not end with a newline, one is
+/// added so that whatever is written next starts on its own line.
 ///
 /// Returns an error on failure or an empty result on success.
-pub fn write_file(mut outf: &File, inf: &String) -> io::Result<()> {
-    let content = read_to_string(inf)?;
-    outf.write_all(content.as_bytes())
+pub fn write_file<W: Write>(out: &mut W, inf: &str) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(inf)?);
+    let mut last = None;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        out.write_all(buf)?;
+        last = buf.last().copied();
+        let len = buf.len();
+        reader.consume(len);
+    }
+    if last.is_some_and(|b| b != b'\n') {
+        out.write_all(b"\n")?;
+    }
+    Ok(())
 }
 
-/// Read an upgrade file and write its contents to the output file
+/// Read an upgrade file and write its contents to the output
 ///
 /// Read the upgrade file (inf) and write its contents, minus the
-/// "BEGIN;" and "COMMIT;" lines, to the output file handle (outf).
+/// "BEGIN;" and "COMMIT;" lines, to the output writer (out). The file
+/// is read a line at a time.
+///
+/// Returns an error on failure or an empty Result on success.
+pub fn write_upgrade<W: Write>(out: &mut W, inf: &str) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(inf)?);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        let text = line.strip_suffix('\n').unwrap_or(&line);
+        if ! TRANSACTION_REGEX.is_match(text) {
+            writeln!(out, "{}", text)?;
+        }
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Size, in bytes, at which upgrade files are memory-mapped by default
+#[cfg(feature = "mmap")]
+pub const MMAP_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Matches the "BEGIN;" and "COMMIT;" lines of a mapped upgrade
+#[cfg(feature = "mmap")]
+static TRANSACTION_BYTES_REGEX: LazyLock<regex::bytes::Regex> =
+    LazyLock::new(|| regex::bytes::Regex::new(r"^\s*(?:BEGIN|COMMIT);").unwrap());
+
+/// Read a memory-mapped upgrade file and write its contents to the output
+///
+/// Works like write_upgrade, but scans the file through a memory map
+/// instead of copying each line into a buffer, so that very large
+/// upgrades do not add to the program's memory use. Lines are copied
+/// as bytes and need not be valid UTF-8.
+///
+/// Returns an error on failure or an empty Result on success.
+#[cfg(feature = "mmap")]
+pub fn write_upgrade_mapped<W: Write>(out: &mut W, inf: &str) -> io::Result<()> {
+    let file = File::open(inf)?;
+    // SAFETY: The map is only read, and nothing should change an
+    // upgrade file while a script is being generated from it.
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    if map.is_empty() {
+        return Ok(());
+    }
+    let data = map.strip_suffix(b"\n").unwrap_or(&map);
+    for line in data.split(|&b| b == b'\n') {
+        if ! TRANSACTION_BYTES_REGEX.is_match(line) {
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// Options for the text that is added around the upgrades in a script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptOptions {
+    /// Wrap the upgrades in a transaction, with "BEGIN;" at the end
+    /// of the preamble and "COMMIT;" after the upgrades. Defaults to
+    /// true.
+    pub transaction: bool,
+    /// Add the block that updates the auditor tables. Defaults to
+    /// true.
+    pub auditor_block: bool,
+}
+
+impl Default for ScriptOptions {
+    fn default() -> Self {
+        ScriptOptions {
+            transaction: true,
+            auditor_block: true,
+        }
+    }
+}
+
+/// Options for naming and placing the upgrade script file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputOptions {
+    /// Directory where the script is written. Defaults to
+    /// VERSION_UPGRADE_DIR, relative to the current directory.
+    pub directory: PathBuf,
+    /// Prefix added to the file name. Defaults to no prefix.
+    pub prefix: String,
+    /// Extension of the file name, without the leading period.
+    /// Defaults to "sql".
+    pub extension: String,
+    /// Overwrite an existing file of the same name. Defaults to false.
+    pub clobber: bool,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            directory: PathBuf::from(VERSION_UPGRADE_DIR),
+            prefix: String::new(),
+            extension: String::from("sql"),
+            clobber: false,
+        }
+    }
+}
+
+impl OutputOptions {
+    /// Get the path of the script for upgrading between two versions
+    ///
+    /// The file is named like the upgrade scripts that come with
+    /// Evergreen, A.B.C-X.Y.Z-upgrade-db.sql, after the prefix.
+    ///
+    /// ```
+    /// use mkdbupgrade::OutputOptions;
+    /// use std::path::Path;
+    ///
+    /// let options = OutputOptions { prefix: "cwmars_".to_string(), ..Default::default() };
+    /// assert_eq!(options.path("3.14.5", "3.15.4"),
+    ///            Path::new("Open-ILS/src/sql/Pg/version-upgrade/cwmars_3.14.5-3.15.4-upgrade-db.sql"));
+    /// ```
+    pub fn path(&self, from_version: &str, version: &str) -> PathBuf {
+        self.directory.join(format!("{}{}-{}-upgrade-db.{}", self.prefix, from_version, version, self.extension))
+    }
+
+    /// Create the script file at a path for writing
+    ///
+    /// Unless clobber is set, the file must not already exist. The
+    /// check is made by the operating system as the file is created,
+    /// so nothing can create the file in between, and an existing
+    /// file is an error of kind AlreadyExists. With clobber, an
+    /// existing file is truncated.
+    ///
+    /// ```
+    /// use mkdbupgrade::OutputOptions;
+    /// use std::io::ErrorKind;
+    ///
+    /// let dir = tempfile::tempdir()?;
+    /// let mut options = OutputOptions { directory: dir.path().to_path_buf(), ..Default::default() };
+    /// let path = options.path("3.14.5", "3.15.4");
+    /// options.create(&path)?;
+    /// assert_eq!(options.create(&path).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    /// options.clobber = true;
+    /// options.create(&path)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn create(&self, path: &Path) -> io::Result<File> {
+        if self.clobber {
+            File::create(path)
+        } else {
+            OpenOptions::new().write(true).create_new(true).open(path)
+        }
+    }
+
+    /// Write a whole script to a path without leaving part of one there
+    ///
+    /// The script is written to a temporary file in the same directory,
+    /// which is then renamed to the path. Unless clobber is set, the
+    /// path is first claimed with create, so an existing file is an
+    /// error of kind AlreadyExists, and it is removed again if the
+    /// script cannot be written. With clobber, an existing file is
+    /// left as it was on failure.
+    ///
+    /// ```
+    /// use mkdbupgrade::OutputOptions;
+    ///
+    /// let dir = tempfile::tempdir()?;
+    /// let options = OutputOptions { directory: dir.path().to_path_buf(), ..Default::default() };
+    /// let path = options.path("3.14.5", "3.15.4");
+    /// options.write_script(&path, b"SELECT 1;\n")?;
+    /// assert!(options.write_script(&path, b"SELECT 2;\n").is_err());
+    /// assert_eq!(std::fs::read(&path)?, b"SELECT 1;\n");
+    /// assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn write_script(&self, path: &Path, script: &[u8]) -> io::Result<()> {
+        self.write_script_with(path, |out| out.write_all(script))
+    }
+
+    /// Write a script to a path as it is generated
+    ///
+    /// Like write_script, but the script is written by calling write
+    /// with a buffered writer for the temporary file, so that it need
+    /// not be held in memory first. Neither file is created until
+    /// something is written, so an error before then leaves the path
+    /// untouched, and one after is cleaned up as for write_script.
+    /// Returns what write returns on success.
+    ///
+    /// ```
+    /// # #[cfg(feature = "fs")]
+    /// # fn main() -> Result<(), mkdbupgrade::MkdbError> {
+    /// use mkdbupgrade::{OutputOptions, UpgradeScriptBuilder};
+    ///
+    /// let dir = tempfile::tempdir()?;
+    /// let options = OutputOptions { directory: dir.path().to_path_buf(), ..Default::default() };
+    /// let path = options.path("3.14.0", "3.14.1");
+    /// let builder = UpgradeScriptBuilder::from_dirs("tests/fixtures/rel_3_14_0", "tests/fixtures/rel_3_14_1");
+    /// let stats = options.write_script_with(&path, |mut out| builder.build_to(&mut out))?;
+    /// assert_eq!(stats.bytes_written, std::fs::metadata(&path)?.len());
+    /// # Ok(())
+    /// # }
+    /// # #[cfg(not(feature = "fs"))]
+    /// # fn main() {}
+    /// ```
+    pub fn write_script_with<T, E, F>(&self, path: &Path, write: F) -> Result<T, E>
+    where
+        E: From<io::Error>,
+        F: FnOnce(&mut dyn Write) -> Result<T, E>,
+    {
+        let mut script = PendingScript { options: self, path, claimed: false, temp: None, out: None };
+        let value = write(&mut script)?;
+        script.finish()?;
+        Ok(value)
+    }
+}
+
+/// The temporary file that a script is written to before it is renamed
+///
+/// The file, and the path it is for unless clobbering, are created on
+/// the first write. Whatever was created is removed when it is dropped
+/// without being finished.
+struct PendingScript<'a> {
+    options: &'a OutputOptions,
+    path: &'a Path,
+    claimed: bool,
+    temp: Option<PathBuf>,
+    out: Option<BufWriter<File>>,
+}
+
+impl PendingScript<'_> {
+    /// Get the temporary file, creating it if need be
+    fn file(&mut self) -> io::Result<&mut BufWriter<File>> {
+        let out = match self.out.take() {
+            Some(out) => out,
+            None => {
+                if !self.options.clobber {
+                    self.options.create(self.path)?;
+                    self.claimed = true;
+                }
+                let (temp, file) = create_temp(self.path)?;
+                self.temp = Some(temp);
+                BufWriter::new(file)
+            },
+        };
+        Ok(self.out.insert(out))
+    }
+
+    /// Sync the temporary file and rename it to the path
+    ///
+    /// The file is created even if nothing was written to it.
+    fn finish(mut self) -> io::Result<()> {
+        let out = self.file()?;
+        out.flush()?;
+        out.get_ref().sync_all()?;
+        // Close the file so that it can be renamed on any platform.
+        self.out = None;
+        if let Some(temp) = &self.temp {
+            std::fs::rename(temp, self.path)?;
+        }
+        self.temp = None;
+        self.claimed = false;
+        Ok(())
+    }
+}
+
+impl Write for PendingScript<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.out {
+            Some(out) => out.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for PendingScript<'_> {
+    fn drop(&mut self) {
+        // Clean up as well as we can; the error has been reported.
+        self.out = None;
+        if let Some(temp) = &self.temp {
+            let _ = std::fs::remove_file(temp);
+        }
+        if self.claimed {
+            let _ = std::fs::remove_file(self.path);
+        }
+    }
+}
+
+/// Number of names tried for a temporary file before giving up
+const TEMP_ATTEMPTS: u32 = 100;
+
+/// Create a hidden temporary file next to a path
+///
+/// The name has the process id and the time in it. A file of the
+/// same name, such as one left by a run that was killed, is never
+/// reused; another name is tried instead. Returns the name of the
+/// new file and the file, or an error that is never AlreadyExists,
+/// which would be mistaken for the path itself existing.
+fn create_temp(path: &Path) -> io::Result<(PathBuf, File)> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+    for attempt in 0..TEMP_ATTEMPTS {
+        let mut name = OsString::from(".");
+        name.push(path.file_name().unwrap_or_default());
+        name.push(format!(".{}.{}.tmp", std::process::id(), nanos.wrapping_add(attempt)));
+        let name = path.with_file_name(name);
+        match OpenOptions::new().write(true).create_new(true).open(&name) {
+            Ok(file) => return Ok((name, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::other(format!("unable to create a temporary file for {}", path.display())))
+}
+
+/// Write the preamble of an upgrade script
+///
+/// Writes a comment naming the versions, sets the eg_version psql
+/// variable used by the upgrades, and begins the transaction if the
+/// options call for one.
 ///
 /// Returns an error on failure or an empty Result on success.
-pub fn write_upgrade(mut outf: &File, inf: &String) -> io::Result<()> {
-    let lines = read_to_string(inf)?;
-    let re = Regex::new(r"^\s*(?:BEGIN|COMMIT);").unwrap();
-    for line in lines.split_terminator("\n").collect::<Vec<&str>>() {
-        if ! re.is_match(line) {
-            writeln!(outf, "{}", line)?;
+///
+/// ```
+/// use mkdbupgrade::{ScriptOptions, write_preamble};
+///
+/// let options = ScriptOptions::default();
+/// let mut out: Vec<u8> = Vec::new();
+/// write_preamble(&mut out, "3.14.5", "3.15.4", &options)?;
+/// assert_eq!(String::from_utf8(out).unwrap(), "\
+/// -- Upgrade script for Evergreen 3.14.5 to 3.15.4
+/// \\set eg_version '''3.15.4'''
+///
+/// BEGIN;
+/// ");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn write_preamble<W: Write>(out: &mut W, from_version: &str, version: &str, options: &ScriptOptions) -> io::Result<()> {
+    // Assemble the text so that it is written all at once.
+    let mut text = format!("-- Upgrade script for Evergreen {from_version} to {version}\n\\set eg_version '''{version}'''\n");
+    if options.transaction {
+        text.push_str("\nBEGIN;\n");
+    }
+    out.write_all(text.as_bytes())
+}
+
+/// Check an upgrade script for signs of damage
+///
+/// Makes a few structural checks that catch accidental changes, such
+/// as while reviewing the script in an editor: the preamble must be
+/// present, and each "BEGIN;" must be closed by a "COMMIT;" or
+/// "ROLLBACK;" before the next one. The SQL itself is not checked.
+///
+/// Returns a description of each problem found, which is empty if the
+/// script looks sound.
+///
+/// ```
+/// use mkdbupgrade::validate_script;
+///
+/// let script = "\
+/// -- Upgrade script for Evergreen 3.14.0 to 3.14.1
+/// \\set eg_version '''3.14.1'''
+///
+/// BEGIN;
+/// CREATE INDEX foo_idx ON actor.usr (id);
+/// ";
+/// assert_eq!(validate_script(script), vec!["BEGIN on line 4 is never committed"]);
+/// ```
+pub fn validate_script(script: &str) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    if !script.lines().any(|l| l.starts_with("-- Upgrade script for Evergreen ")) {
+        problems.push("the upgrade script comment is missing".to_string());
+    }
+    if !script.lines().any(|l| l.starts_with("\\set eg_version ")) {
+        problems.push("the eg_version variable is not set".to_string());
+    }
+    let mut open: Option<usize> = None;
+    let mut quotes = DollarQuotes::default();
+    for (n, line) in script.lines().enumerate() {
+        let Some(caps) = quotes.statement(line) else { continue };
+        let lineno = n + 1;
+        if caps.get(1).is_some() {
+            if let Some(begun) = open {
+                problems.push(format!("BEGIN on line {lineno} is inside the transaction begun on line {begun}"));
+            }
+            open = Some(lineno);
+        } else if open.take().is_none() {
+            problems.push(format!("{} on line {lineno} has no matching BEGIN", caps[2].to_uppercase()));
         }
     }
+    if let Some(begun) = open {
+        problems.push(format!("BEGIN on line {begun} is never committed"));
+    }
+    problems
+}
+
+/// Text that updates the auditor tables at the end of a script
+const AUDITOR_BLOCK: &str = "\
+-- Update auditor tables to catch changes in source tables.
+-- Can be removed/skipped if there were no schema changes.
+SELECT auditor.update_auditors();
+";
+
+/// Write the block that updates the auditor tables
+///
+/// The auditor tables need updating when their source tables
+/// change. Nothing is written if the options leave the block out.
+///
+/// Returns an error on failure or an empty Result on success.
+///
+/// ```
+/// use mkdbupgrade::{ScriptOptions, write_auditor_block};
+///
+/// let options = ScriptOptions::default();
+/// let mut out: Vec<u8> = Vec::new();
+/// write_auditor_block(&mut out, &options)?;
+/// assert_eq!(String::from_utf8(out).unwrap(), "\
+/// -- Update auditor tables to catch changes in source tables.
+/// -- Can be removed/skipped if there were no schema changes.
+/// SELECT auditor.update_auditors();
+/// ");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn write_auditor_block<W: Write>(out: &mut W, options: &ScriptOptions) -> io::Result<()> {
+    if options.auditor_block {
+        out.write_all(AUDITOR_BLOCK.as_bytes())?;
+    }
     Ok(())
 }
 
-/// Open the output file in the user's EDITOR for review
+/// Open the output file in the user's editor for review
+///
+/// Opens the output file in the program set in the first of the
+/// user's GIT_EDITOR, VISUAL, or EDITOR environment variables that is
+/// set. Falls back to vi on Unix systems if none of them are set.
+/// The variable may include arguments for the editor, and may use
+/// shell quoting for a path or arguments with spaces.
 ///
-/// Opens the output file in the program set in the user's EDITOR
-/// environment variable.
+/// Waits for the editor to exit, like git does when editing a commit
+/// message.
 ///
-/// Returns any errors that occur, usually if the EDITOR variable is
-/// not set, or the editor cannot be run.
+/// Returns any errors that occur, usually if no variable is set on
+/// other systems, or the editor cannot be run. Returns
+/// MkdbError::Status if the editor exits with a failure status.
 ///
 /// Returns an empty result on success.
-pub fn review_file(file: &String) -> Result<(), Box<dyn Error>> {
-    let editor = match var("EDITOR") {
-        Ok(ed) => ed,
-        Err(e) => return Err(Box::new(e)),
-    };
-    let args: Vec<&str> = editor.split_whitespace().collect();
-    let mut cmd = Command::new(args[0]);
-    for arg in &args[1..] {
-        cmd.arg(arg);
-    }
-    cmd.arg(file);
-    match cmd.spawn() {
-        Ok(mut child) => {
-            match child.wait() {
-                Ok(_) => Ok(()),
-                Err(e) => Err(Box::new(e)),
-            }
-        },
-        Err(e) => Err(Box::new(e)),
+pub fn review_file<P: AsRef<Path>>(file: P) -> Result<(), MkdbError> {
+    run_on_file(&get_editor()?, file.as_ref())
+}
+
+/// Open the output file in the user's pager for reading
+///
+/// Opens the output file in the program set in the user's PAGER
+/// environment variable. Falls back to less, or more if less cannot
+/// be found, if PAGER is not set.
+///
+/// Returns any errors that occur, usually if the pager cannot be
+/// run. Returns MkdbError::Status if the pager exits with a failure
+/// status.
+///
+/// Returns an empty result on success.
+pub fn page_file<P: AsRef<Path>>(file: P) -> Result<(), MkdbError> {
+    match var("PAGER") {
+        Ok(pager) if !pager.trim().is_empty() => return run_on_file(&pager, file.as_ref()),
+        Ok(_) | Err(VarError::NotPresent) => (),
+        Err(e) => return Err(MkdbError::Pager(e)),
+    }
+    match run_on_file("less", file.as_ref()) {
+        Err(MkdbError::Io(e)) if e.kind() == io::ErrorKind::NotFound => run_on_file("more", file.as_ref()),
+        result => result,
+    }
+}
+
+/// Run a hook command on a generated upgrade script
+///
+/// The command line is split like the editor command in review_file.
+/// The path of the script is added as the command's last argument and
+/// is also set in the MKDBUPGRADE_OUTPUT environment variable.
+///
+/// Returns MkdbError::Status if the command exits with a failure
+/// status, or any other error that occurs while running it.
+pub fn run_hook<P: AsRef<Path>>(command: &str, file: P) -> Result<(), MkdbError> {
+    let args = split_command(command);
+    if args.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command").into());
+    }
+    let file = file.as_ref();
+    let status = Command::new(&args[0]).args(&args[1..]).arg(file).env("MKDBUPGRADE_OUTPUT", file).status()?;
+    if !status.success() {
+        return Err(MkdbError::Status { program: args[0].clone(), status });
+    }
+    Ok(())
+}
+
+/// Run a command with a file as its last argument and wait for it
+///
+/// Returns MkdbError::Status if the command exits with a failure
+/// status.
+fn run_on_file(command: &str, file: &Path) -> Result<(), MkdbError> {
+    let args = split_command(command);
+    let status = Command::new(&args[0]).args(&args[1..]).arg(file).status()?;
+    if !status.success() {
+        return Err(MkdbError::Status { program: args[0].clone(), status });
+    }
+    Ok(())
+}
+
+/// Pipe a script through a command and return the command's output
+///
+/// The command line is split like the editor command in review_file,
+/// and is meant for a program that reads SQL on its standard input
+/// and writes it reformatted to its standard output, such as
+/// pg_format. The command's standard error is passed through.
+///
+/// Returns the command's output on success. Returns
+/// MkdbError::Status if the command exits with a failure status, or
+/// any other error that occurs while running it.
+///
+/// ```
+/// # #[cfg(unix)] {
+/// use mkdbupgrade::pipe_script;
+///
+/// assert_eq!(pipe_script("tr a-z A-Z", b"select 1;\n")?, b"SELECT 1;\n");
+/// # }
+/// # Ok::<(), mkdbupgrade::MkdbError>(())
+/// ```
+pub fn pipe_script(command: &str, script: &[u8]) -> Result<Vec<u8>, MkdbError> {
+    let args = split_command(command);
+    if args.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command").into());
+    }
+    let output = run_with_input(Command::new(&args[0]).args(&args[1..]).stdout(Stdio::piped()), script)?;
+    if !output.status.success() {
+        return Err(MkdbError::Status { program: args[0].clone(), status: output.status });
+    }
+    Ok(output.stdout)
+}
+
+/// Make a version of an upgrade script that always rolls back
+///
+/// Removes every line that begins or ends a transaction, in any case
+/// and form, such as "commit;", "END;", or "START TRANSACTION;", and
+/// wraps what is left in a single transaction that ends with
+/// "ROLLBACK;". Lines inside dollar-quoted function bodies are kept.
+/// Prepended, appended, and moved files are copied into the script
+/// as they are, so a "commit;" in one of them must not be left to
+/// commit the test run. Running the result shows whether the script
+/// applies cleanly without changing the database. Upgrades that
+/// cannot run inside a transaction will fail.
+///
+/// ```
+/// use mkdbupgrade::make_test_script;
+///
+/// assert_eq!(make_test_script("BEGIN;\nSELECT 1;\ncommit work;\n"), "BEGIN;\nSELECT 1;\nROLLBACK;\n");
+/// ```
+pub fn make_test_script(script: &str) -> String {
+    let mut out = String::from("BEGIN;\n");
+    let mut quotes = DollarQuotes::default();
+    for line in script.lines().filter(|l| quotes.statement(l).is_none()) {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str("ROLLBACK;\n");
+    out
+}
+
+/// Test an upgrade script against a database with psql
+///
+/// Runs the script made by make_test_script with psql, connecting
+/// with the conninfo string or URI, and stops at the first error.
+/// Nothing is committed, but the database should still be a
+/// disposable copy. psql must be in the PATH.
+///
+/// Returns an empty result if the script applies cleanly. Returns
+/// MkdbError::TestRun with psql's error output if it does not, or
+/// any error that occurs running psql.
+pub fn test_run_script(conninfo: &str, script: &str) -> Result<(), MkdbError> {
+    let mut cmd = Command::new("psql");
+    cmd.args(["-X", "-q", "-v", "ON_ERROR_STOP=1", "-f", "-", "-d", conninfo])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    let output = run_with_input(&mut cmd, make_test_script(script).as_bytes())?;
+    if !output.status.success() {
+        return Err(MkdbError::TestRun {
+            status: output.status,
+            errors: String::from_utf8_lossy(&output.stderr).trim_end().to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Run a command with input written to its standard input
+///
+/// Returns the command's output, with whatever it was set up to
+/// capture, once it exits.
+fn run_with_input(cmd: &mut Command, input: &[u8]) -> io::Result<Output> {
+    let mut child = cmd.stdin(Stdio::piped()).spawn()?;
+    // Write from another thread so that a command that writes before
+    // it has read all of its input cannot block us both.
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let (written, output) = std::thread::scope(|s| {
+        let writer = s.spawn(move || stdin.write_all(input));
+        let output = child.wait_with_output();
+        (writer.join().expect("writer thread panicked"), output)
+    });
+    let output = output?;
+    // A command that fails may not read all of its input, so its exit
+    // status says more than the broken pipe does.
+    if output.status.success() {
+        written?;
+    }
+    Ok(output)
+}
+
+/// Split a command line into the program and its arguments
+///
+/// Quoting works like in the shell, so a program path with spaces in
+/// it can be quoted. Falls back to splitting on whitespace if the
+/// quotes are not balanced.
+fn split_command(command: &str) -> Vec<String> {
+    match shlex::split(command) {
+        Some(args) if !args.is_empty() => args,
+        _ => command.split_whitespace().map(String::from).collect(),
+    }
+}
+
+/// Get the user's editor command from the environment
+///
+/// Variables that are set but empty are ignored.
+fn get_editor() -> Result<String, MkdbError> {
+    for name in ["GIT_EDITOR", "VISUAL", "EDITOR"] {
+        match var(name) {
+            Ok(editor) if !editor.trim().is_empty() => return Ok(editor),
+            Ok(_) | Err(VarError::NotPresent) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if cfg!(unix) {
+        Ok(String::from("vi"))
+    } else {
+        Err(VarError::NotPresent.into())
     }
 }
 
 /// Generate a regular expression that matches any string in a vector
 ///
-/// Takes a Vector of Strings and returns a Regex that ors the strings
+/// Takes a slice of Strings and returns a Regex that ors the strings
 /// in a non-capture group.
-pub fn make_or_regex(strings: &Vec<String>) -> Regex {
-    let mut restr = String::from("(?:");
-    let mut add_pipe = false;
-    for entry in strings {
-        if add_pipe {
-            restr.push('|');
-        }
-        restr.push_str(&entry);
-        add_pipe = true;
-    }
-    restr.push(')');
-    Regex::new(&restr).unwrap()
+///
+/// Panics if the strings do not make a valid regular expression. Use
+/// build_move_matcher to get an error instead.
+pub fn make_or_regex(strings: &[String]) -> Regex {
+    Regex::new(&or_pattern(strings)).unwrap()
+}
+
+/// Join strings into a regular expression alternation
+fn or_pattern(strings: &[String]) -> String {
+    format!("(?:{})", strings.join("|"))
+}
+
+/// Matches upgrade paths against a list of patterns
+///
+/// Used to pick out the upgrades to move after the main transaction,
+/// or to skip. A matcher made from no patterns matches nothing. Made
+/// by build_move_matcher or build_literal_matcher.
+#[derive(Debug, Clone)]
+pub struct MoveMatcher {
+    regex: Option<Regex>,
+}
+
+impl MoveMatcher {
+    /// Check if a path matches any of the patterns
+    pub fn matches(&self, path: &str) -> bool {
+        self.regex.as_ref().is_some_and(|re| re.is_match(path))
+    }
+
+    /// Split paths into those that do not match and those that do
+    ///
+    /// Returns the paths that do not match followed by those that
+    /// do, each in their original order.
+    ///
+    /// ```
+    /// use mkdbupgrade::build_literal_matcher;
+    ///
+    /// let paths = vec!["1440.data.sql".to_string(), "1441.schema.sql".to_string()];
+    /// let (kept, moved) = build_literal_matcher(&["1440".to_string()]).partition(paths);
+    /// assert_eq!(kept, vec!["1441.schema.sql"]);
+    /// assert_eq!(moved, vec!["1440.data.sql"]);
+    /// ```
+    pub fn partition(&self, paths: Vec<String>) -> (Vec<String>, Vec<String>) {
+        let (matched, unmatched): (Vec<String>, Vec<String>) = paths.into_iter().partition(|p| self.matches(p));
+        (unmatched, matched)
+    }
+}
+
+/// Build a matcher for upgrades that match any of the patterns
+///
+/// Each pattern is a regular expression that may match any part of
+/// an upgrade's path.
+///
+/// Returns MkdbError::Pattern if the patterns do not make a valid
+/// regular expression.
+///
+/// ```
+/// use mkdbupgrade::build_move_matcher;
+///
+/// let matcher = build_move_matcher(&["1440".to_string(), "1443".to_string()])?;
+/// assert!(matcher.matches("Open-ILS/src/sql/Pg/upgrade/1443.schema.example.sql"));
+/// assert!(!matcher.matches("Open-ILS/src/sql/Pg/upgrade/1441.schema.example.sql"));
+/// # Ok::<(), mkdbupgrade::MkdbError>(())
+/// ```
+pub fn build_move_matcher(patterns: &[String]) -> Result<MoveMatcher, MkdbError> {
+    let regex = if patterns.is_empty() {
+        None
+    } else {
+        Some(Regex::new(&or_pattern(patterns))?)
+    };
+    Ok(MoveMatcher { regex })
+}
+
+/// Build a matcher for upgrades that contain any of the strings
+///
+/// Works like build_move_matcher, but the strings are matched
+/// literally, so characters like "." and "+" have no special meaning.
+///
+/// ```
+/// use mkdbupgrade::build_literal_matcher;
+///
+/// let matcher = build_literal_matcher(&["1440.data".to_string(), "(".to_string()]);
+/// assert!(matcher.matches("Open-ILS/src/sql/Pg/upgrade/1440.data.example.sql"));
+/// assert!(!matcher.matches("Open-ILS/src/sql/Pg/upgrade/1440-data.example.sql"));
+/// ```
+pub fn build_literal_matcher(strings: &[String]) -> MoveMatcher {
+    let escaped: Vec<String> = strings.iter().map(|s| regex::escape(s)).collect();
+    // Escaped strings always make a valid regular expression.
+    build_move_matcher(&escaped).unwrap()
 }
@@ -0,0 +1,138 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::builder::styling::{AnsiColor, Style};
+use clap::{ColorChoice, ValueEnum};
+use std::env::var_os;
+use std::io::{IsTerminal, stderr};
+use std::sync::OnceLock;
+
+/// Format of the messages written to standard error
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Plain text messages for people
+    #[default]
+    Text,
+    /// One JSON object per line for CI systems
+    Json,
+}
+
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+static COLOR: OnceLock<bool> = OnceLock::new();
+
+/// Set the format and color used for all log messages
+///
+/// Only the first call has any effect. Messages are written as text
+/// without color if this is never called. The color should come from
+/// color_choice. Errors and warnings are colored when it is Always,
+/// or when it is Auto and standard error is a terminal that supports
+/// color. JSON messages are never colored.
+pub fn init(format: LogFormat, color: ColorChoice) {
+    let _ = FORMAT.set(format);
+    let color = match color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => color_forced() || stderr().is_terminal() && var_os("TERM").is_none_or(|t| t != "dumb"),
+    };
+    let _ = COLOR.set(color);
+}
+
+/// Get the choice of color for all output
+///
+/// Color is never used if the NO_COLOR environment variable is set
+/// to anything but an empty string (see https://no-color.org), no
+/// matter what else asks for it, including --color=always. Otherwise
+/// the requested choice is used. Anything that writes in color should
+/// check this first.
+pub fn color_choice(requested: ColorChoice) -> ColorChoice {
+    match var_os("NO_COLOR") {
+        Some(v) if !v.is_empty() => ColorChoice::Never,
+        _ => requested,
+    }
+}
+
+/// Check if CLICOLOR_FORCE asks for color even when not on a terminal
+fn color_forced() -> bool {
+    var_os("CLICOLOR_FORCE").is_some_and(|v| !v.is_empty() && v != "0")
+}
+
+/// Log an error message with optional named fields
+pub fn error(message: &str, fields: &[(&str, &str)]) {
+    log("error", message, fields);
+}
+
+/// Log a warning message with optional named fields
+pub fn warn(message: &str, fields: &[(&str, &str)]) {
+    log("warn", message, fields);
+}
+
+/// Log an informational message with optional named fields
+pub fn info(message: &str, fields: &[(&str, &str)]) {
+    log("info", message, fields);
+}
+
+/// Write a message to standard error in the configured format
+///
+/// The fields are only written in JSON format. Text messages are
+/// expected to already mention anything of interest.
+fn log(level: &str, message: &str, fields: &[(&str, &str)]) {
+    match FORMAT.get().copied().unwrap_or_default() {
+        LogFormat::Text => {
+            let style = match level {
+                "error" => AnsiColor::Red.on_default().bold(),
+                "warn" => AnsiColor::Yellow.on_default(),
+                _ => Style::new(),
+            };
+            if COLOR.get().copied().unwrap_or_default() {
+                eprintln!("{}{message}{}", style.render(), style.render_reset());
+            } else {
+                eprintln!("{message}");
+            }
+        },
+        LogFormat::Json => {
+            let mut record = format!("{{\"level\":{},\"message\":{}",
+                                     json_string(level), json_string(message));
+            for (name, value) in fields {
+                record.push_str(&format!(",{}:{}", json_string(name), json_string(value)));
+            }
+            record.push('}');
+            eprintln!("{record}");
+        },
+    }
+}
+
+/// Quote and escape a string as a JSON string literal
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -17,19 +17,124 @@
  * You should have received a copy of the GNU General Public License
  * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
  */
-use clap::Parser;
-use regex::Regex;
-use std::fs::File;
-use std::io::Write;
+use clap::builder::TypedValueParser;
+use clap::error::ErrorKind;
+use clap::{Args, ColorChoice, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use git2::Repository;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::fs::{OpenOptions, read_to_string};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::process::exit;
+use std::process::{Command, exit};
 use mkdbupgrade::*;
 
+mod logging;
+
+use logging::LogFormat;
+
+/// Exit status codes documented in the help text
+const EXIT_CODES: &str = "\
+Exit status:
+  0  Success
+  1  General failure
+  2  Invalid command line usage
+  3  Not in an Evergreen git repository
+  4  Branch not found or current HEAD is not a branch
+  5  Unable to determine an Evergreen version
+  6  Error reading upgrades from git
+  7  No upgrades were found
+  8  Output file exists
+  9  Error reading or writing a file
+ 10  The script failed its test run
+ 11  The post-generation hook failed
+ 12  The from and to revisions are the same
+ 13  Error writing to standard output";
+
+/// Classes of failure, each with its own exit status code
+///
+/// Keep EXIT_CODES in sync with this.  Code 2 is used by clap for
+/// command line usage errors.
+#[derive(Clone, Copy, Debug)]
+enum Failure {
+    General = 1,
+    NotRepository = 3,
+    BranchNotFound = 4,
+    UnknownVersion = 5,
+    Git = 6,
+    NoUpgrades = 7,
+    OutputExists = 8,
+    Io = 9,
+    TestRun = 10,
+    Hook = 11,
+    SameRevision = 12,
+    Output = 13,
+}
+
+impl Failure {
+    /// Exit the program with the code for this class of failure
+    fn exit(self) -> ! {
+        exit(self as i32)
+    }
+}
+
+impl From<&MkdbError> for Failure {
+    fn from(e: &MkdbError) -> Self {
+        match e {
+            MkdbError::Git(_) => Failure::Git,
+            MkdbError::Io(_) => Failure::Io,
+            MkdbError::Version(_) => Failure::UnknownVersion,
+            MkdbError::Head(_) => Failure::BranchNotFound,
+            MkdbError::File { .. } => Failure::Io,
+            MkdbError::Files(_) => Failure::Io,
+            MkdbError::NoUpgrades => Failure::NoUpgrades,
+            MkdbError::Pattern(_) => Failure::General,
+            MkdbError::Editor(_) => Failure::General,
+            MkdbError::TestRun { .. } => Failure::TestRun,
+            MkdbError::Pager(_) => Failure::General,
+            MkdbError::Status { .. } => Failure::General,
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
-#[command(about, long_about)]
+#[command(about, long_about, args_conflicts_with_subcommands = true)]
+#[command(after_help = EXIT_CODES)]
 /// Make a custom database upgrade script from one version of Evergreen to another.
+///
+/// When run without a subcommand, mkdbupgrade generates the upgrade
+/// script as with the generate subcommand.
 pub struct Cli {
-    /// Evergreen git branch we are upgrading from
+    #[command(subcommand)]
+    command: Option<Commands>,
+    #[command(flatten)]
+    branches: Option<BranchArgs>,
+    #[command(flatten)]
+    options: GenerateOptions,
+    /// Format of error and warning messages written to standard error.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// When to use color in help, usage errors, and messages. The NO_COLOR environment variable overrides this.
+    #[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Generate the database upgrade script. This is the default.
+    Generate(Box<GenerateArgs>),
+    /// List the new upgrades in the current branch, one per line.
+    List(BranchArgs),
+    /// Show upgrades added (A), removed (D), or changed (M) in the current branch.
+    Diff(BranchArgs),
+    /// Show the Evergreen versions of the from and current branches.
+    Versions(BranchArgs),
+}
+
+/// Options for locating the branches and their versions
+#[derive(Args, Debug)]
+struct BranchArgs {
+    /// Evergreen git branch we are upgrading from. Prefix with "tag:" to use a tag or "rev:" to use a commit id instead.
     #[arg(short,long)]
     from_branch: String,
     /// Evergreen version we are upgrading from. Calculated from previous branch name if absent.
@@ -38,34 +143,183 @@ pub struct Cli {
     /// Version of Evergreen we are upgrading to. Calculated from current branch name if absent. An error occurs if it cannot be calculated.
     #[arg(short,long)]
     version: Option<String>,
+}
+
+/// Arguments for the generate subcommand
+#[derive(Args, Debug)]
+struct GenerateArgs {
+    #[command(flatten)]
+    branches: BranchArgs,
+    #[command(flatten)]
+    options: GenerateOptions,
+}
+
+/// Options for generating the upgrade script
+#[derive(Args, Debug)]
+struct GenerateOptions {
     /// Database upgrade(s) to move to after the main transaction. May be repeated to move additional upgrades.
     #[arg(short, long="move")]
     moved: Option<Vec<String>>,
+    /// Treat the --move arguments as regular expressions instead of literal strings.
+    #[arg(long)]
+    move_regex: bool,
+    /// Strip the BEGIN and COMMIT lines from moved upgrades and merge them into one transaction after the main one, instead of copying them as they are.
+    #[arg(long, requires = "moved")]
+    strip_moved: bool,
+    /// Write the script even if there are no upgrades, with only the preamble, COMMIT, and auditor block, and exit successfully.
+    #[arg(long)]
+    allow_empty: bool,
     /// Database upgrades(s) to skip. May be repeated to skip additional upgrades
     #[arg(short, long="skip")]
     skipped: Option<Vec<String>>,
     /// File to append to end of output upgrade script. May be repeated to add additional files.
-    #[arg(short,long)]
+    #[arg(short,long, value_parser = expand_path)]
     append_file: Option<Vec<String>>,
     /// File to prepend to beginning of output upgrade script. May be repeated to add additional files.
-    #[arg(short,long)]
+    #[arg(short,long, value_parser = expand_path)]
     prepend_file: Option<Vec<String>>,
+    /// Where to write the prepended files: before the preamble and its BEGIN, or after it, inside the main transaction.
+    #[arg(long, value_enum, value_name = "POSITION", default_value_t = PrependArg::BeforePreamble)]
+    prepend_position: PrependArg,
     /// Output directory where to write the database upgrade script file.
-    #[arg(short='O',long, default_value="Open-ILS/src/sql/Pg/version-upgrade")]
-    output_directory: String,
+    #[arg(short='O',long, default_value=VERSION_UPGRADE_DIR, value_parser = PathParser)]
+    output_directory: PathBuf,
     /// Prefix to add to output file name.
     #[arg(short='P',long)]
     prefix: Option<String>,
+    /// Separator to insert between the prefix and the rest of the output file name.
+    #[arg(long, requires = "prefix")]
+    prefix_separator: Option<String>,
     /// Overwrite an existing output file with the same name. Otherwise an error is signaled if a file of the same name exists.
     #[arg(short='C',long)]
     clobber: bool,
-    /// Review or edit the result in your EDITOR.
+    /// Review or edit the result in your editor. The editor is taken from GIT_EDITOR, VISUAL, or EDITOR, in that order, or is vi if none are set.
     #[arg(short,long)]
     review: bool,
+    /// After the editor exits, check that the reviewed script still has its preamble and balanced BEGIN and COMMIT lines, and warn if not.
+    #[arg(long, requires = "review")]
+    validate_after_edit: bool,
+    /// Read the result in your PAGER, or less if PAGER is not set, without editing it.
+    #[arg(long)]
+    pager: bool,
+    /// Pipe the script through a formatter command, such as pg_format, and write the formatter's output instead.
+    #[arg(long, value_name = "CMD")]
+    format_with: Option<String>,
+    /// Test the script with psql against the database given by a conninfo string or URI, rolling back all changes. Use a disposable database.
+    #[arg(long, value_name = "CONNINFO")]
+    test_run: Option<String>,
+    /// Run a command after the script is written and reviewed. The script's path is added as the last argument and set in MKDBUPGRADE_OUTPUT.
+    #[arg(long, value_name = "CMD")]
+    post_hook: Option<String>,
+    /// Copy the path of the output file to the clipboard after it is written.
+    #[cfg(feature = "clipboard")]
+    #[arg(long)]
+    copy_path: bool,
+    /// Open the output directory in the system file manager after the file is written.
+    #[arg(long)]
+    reveal: bool,
+    /// Number of upgrade files to read at the same time. Defaults to the number of CPUs. 1 reads them one at a time, in order.
+    #[arg(short, long, value_name = "N")]
+    jobs: Option<usize>,
+    /// Number of upgrades at which their files are read in parallel. Fewer are read one at a time.
+    #[arg(long, value_name = "N", default_value_t = PARALLEL_THRESHOLD)]
+    parallel_threshold: usize,
+    /// Print the paths of the upgrades that would be included, one per line, and exit without writing the script.
+    #[arg(long)]
+    list_only: bool,
+}
+
+/// Where the prepended files are written, as given on the command line
+///
+/// The positions are named in kebab case, such as before-preamble.
+/// The library's PrependPosition does not depend on clap.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum PrependArg {
+    /// Before the preamble, and so before the transaction begins
+    #[default]
+    BeforePreamble,
+    /// After the preamble, inside the main transaction, before the
+    /// first upgrade
+    InsideTransaction,
+}
+
+impl From<PrependArg> for PrependPosition {
+    fn from(arg: PrependArg) -> Self {
+        match arg {
+            PrependArg::BeforePreamble => PrependPosition::BeforePreamble,
+            PrependArg::InsideTransaction => PrependPosition::InsideTransaction,
+        }
+    }
+}
+
+///
+/// Quoted paths are not expanded by the shell, so we do it ourselves.
+/// Referring to a variable that is not set is an error.
+#[cfg(feature = "expand")]
+fn expand_path(path: &str) -> Result<String, String> {
+    match shellexpand::full(path) {
+        Ok(p) => Ok(p.into_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Use a path argument as it is, without the expand feature
+#[cfg(not(feature = "expand"))]
+fn expand_path(path: &str) -> Result<String, String> {
+    Ok(path.to_string())
+}
+
+/// Parser for path arguments that may not be valid UTF-8
+///
+/// Paths that are valid UTF-8 are expanded by expand_path. Any other
+/// path cannot name a variable, so it is used as it is rather than
+/// being rejected or converted to a String.
+#[derive(Clone)]
+struct PathParser;
+
+impl TypedValueParser for PathParser {
+    type Value = PathBuf;
+
+    fn parse_ref(&self, cmd: &clap::Command, arg: Option<&clap::Arg>, value: &OsStr) -> Result<PathBuf, clap::Error> {
+        let Some(path) = value.to_str() else {
+            return Ok(PathBuf::from(value));
+        };
+        expand_path(path).map(PathBuf::from).map_err(|e| {
+            let arg = arg.map_or_else(|| "...".to_string(), ToString::to_string);
+            cmd.clone().error(ErrorKind::ValueValidation, format!("invalid value '{}' for '{}': {}", path, arg, e))
+        })
+    }
+}
+
+/// Find the --color choice before the command line is parsed
+///
+/// Help and usage errors are written while parsing, so the choice is
+/// needed first. An invalid choice is ignored here and reported by
+/// the parser.
+fn requested_color() -> ColorChoice {
+    let mut choice = ColorChoice::Auto;
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        let value = if arg == "--color" {
+            args.next()
+        } else {
+            arg.to_str().and_then(|a| a.strip_prefix("--color=")).map(OsString::from)
+        };
+        if let Some(c) = value.and_then(|v| ColorChoice::from_str(v.to_str()?, false).ok()) {
+            choice = c;
+        }
+    }
+    choice
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let color = logging::color_choice(requested_color());
+    let matches = Cli::command().color(color).get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    logging::init(cli.log_format, logging::color_choice(cli.color));
 
     // Assumes we're in the Evergreen git repository with the correct
     // branch checked out. This also makes a quick test if we're in a
@@ -73,225 +327,526 @@ fn main() {
     let repository = match get_repository() {
         Some(r) => r,
         None => {
-            eprintln!("Current directory is not a git repository");
-            exit(1);
-        }
-    };
-    let to_branch = match get_current_branch(&repository) {
-        Ok(b) => b,
-        Err(e) => {
-            eprintln!("{e}");
-            exit(1);
-        },
-    };
-
-    let to_branch_name = match to_branch.name() {
-        Ok(Some(s)) => s,
-        Ok(None) => "unknown branch",
-        Err(e) => {
-            eprintln!("{e}");
-            exit(1);
+            logging::error("Current directory is not a git repository", &[]);
+            Failure::NotRepository.exit();
         }
     };
 
     // Check for the Open-ILS subdirectory as an extra precaution.
     let checkdir = Path::new("Open-ILS");
     if ! checkdir.exists() || ! checkdir.is_dir() {
-        eprintln!("Not in an Evergreen repository, exiting");
-        exit(1);
+        logging::error("Not in an Evergreen repository, exiting", &[]);
+        Failure::NotRepository.exit();
     }
 
+    match cli.command {
+        Some(Commands::Generate(args)) => generate(&repository, *args),
+        Some(Commands::List(args)) => list(&repository, &args),
+        Some(Commands::Diff(args)) => diff(&repository, &args),
+        Some(Commands::Versions(args)) => versions(&repository, &args),
+        None => match cli.branches {
+            Some(branches) => generate(&repository, GenerateArgs { branches, options: cli.options }),
+            None => {
+                Cli::command()
+                    .color(color)
+                    .error(ErrorKind::MissingRequiredArgument,
+                           "the from branch is required, specify it with -f [branch]")
+                    .exit();
+            }
+        },
+    }
+}
+
+/// Look up the current ("to") branch and the "from" revision
+///
+/// The "from" argument is a branch name, or a tag or commit id when
+/// prefixed with "tag:" or "rev:". Exits with an error message if
+/// either cannot be found.
+fn resolve_branches<'r>(repository: &'r Repository, args: &BranchArgs) -> (Revision<'r>, Revision<'r>) {
+    let to_branch = match get_current_branch(repository) {
+        Ok(b) => b,
+        Err(e) => {
+            logging::error(&e.to_string(), &[]);
+            Failure::from(&e).exit();
+        },
+    };
+
     // The "from" or source branch is required, so let's check if it
     // exists.
-    let from_branch = match find_branch(&repository, &cli.from_branch) {
+    let found = if let Some(tag) = args.from_branch.strip_prefix("tag:") {
+        find_tag(repository, tag).map(Revision::Tag)
+    } else if let Some(id) = args.from_branch.strip_prefix("rev:") {
+        find_commit(repository, id).map(Revision::Commit)
+    } else {
+        find_branch(repository, &args.from_branch).map(Revision::Branch)
+    };
+    let from_branch = match found {
         Ok(v) => v,
         Err(e) => {
-            eprintln!("Error finding from branch {}: {}", &cli.from_branch, e);
-            exit(1);
+            logging::error(&format!("Error finding from branch {}: {}", &args.from_branch, e),
+                           &[("branch", &args.from_branch)]);
+            Failure::BranchNotFound.exit();
         }
     };
 
+    (from_branch, Revision::from(to_branch))
+}
+
+/// Determine the "from" and "to" Evergreen versions
+///
+/// Uses the versions from the command line if given, otherwise
+/// calculates them from the branch names. Exits with an error message
+/// if a version cannot be determined.
+fn resolve_versions(args: &BranchArgs, from_branch: &Revision, to_branch: &Revision) -> (String, String) {
+    let to_branch_name = to_branch.name().unwrap_or("unknown branch");
+
     // The version of Evergreen that we're upgrading to.
-    let version = match cli.version {
-        Some(v) => v,
+    let version = match &args.version {
+        Some(v) => given_version(v),
         None => {
-            match get_branch_version(&to_branch) {
+            match get_revision_version(to_branch) {
                 Some(v) => v,
                 None => {
-                    eprintln!("Unable to determine version from branch: {}",
-                              to_branch_name);
-                    eprintln!("Specify the new Evergreen version with -v [version]");
-                    exit(1);
+                    logging::error(&format!("Unable to determine version from branch: {}",
+                                            to_branch_name),
+                                   &[("branch", to_branch_name)]);
+                    logging::info("Specify the new Evergreen version with -v [version]", &[]);
+                    Failure::UnknownVersion.exit();
                 }
             }
         },
     };
 
     // The version of Evergreen that we're upgrading from.
-    let from_version = match cli.from_version {
-        Some(v) => v,
+    let from_version = match &args.from_version {
+        Some(v) => given_version(v),
         None => {
-            match get_branch_version(&from_branch) {
+            match get_revision_version(from_branch) {
                 Some(v) => v,
                 None => {
-                    eprintln!("Unable to determine version from branch: {}",
-                              &cli.from_branch);
-                    eprintln!("Specify the old Evergreen version with -F [version]");
-                    exit(1);
+                    logging::error(&format!("Unable to determine version from branch: {}",
+                                            &args.from_branch),
+                                   &[("branch", &args.from_branch)]);
+                    logging::info("Specify the old Evergreen version with -F [version]", &[]);
+                    Failure::UnknownVersion.exit();
                 }
             }
         },
     };
 
-    // Filename for the database upgrade script.
-    let upgrade_filename = match cli.prefix {
-        Some(p) => format!("{}{}-{}-upgrade-db.sql", p, from_version, version),
-        None => format!("{}-{}-upgrade-db.sql", from_version, version),
+    (from_version, version)
+}
+
+/// Normalize a version given on the command line to the X.Y.Z form
+///
+/// A version in another form, such as 3.15-beta, is used as it is.
+fn given_version(version: &str) -> String {
+    normalize_version(version).map_or_else(|| version.to_string(), Cow::into_owned)
+}
+
+/// Exit with a message if the "from" and "to" revisions are the same commit
+fn check_distinct(from_branch: &Revision, to_branch: &Revision) {
+    let (Ok(from), Ok(to)) = (from_branch.commit(), to_branch.commit()) else {
+        // Any problem reading them is reported when their upgrades are.
+        return;
     };
-    // We're going to use out_path for opening and writing the file.
-    let mut out_path = PathBuf::new();
-    out_path.push(cli.output_directory);
-    out_path.push(upgrade_filename);
-    if out_path.exists() && ! cli.clobber {
-        eprintln!("Output file {} exists, exiting", out_path.display());
-        eprintln!("You can overwrite it with the -C option");
-        exit(1);
+    if from.id() == to.id() {
+        let (from_name, to_name) = (revision_label(from_branch), revision_label(to_branch));
+        logging::error(&format!("The from branch {} and the current branch {} are the same commit, so there is nothing to upgrade",
+                                from_name, to_name),
+                       &[("from_branch", &from_name), ("branch", &to_name)]);
+        logging::info("Check out the branch being upgraded to and give the one upgraded from with -f", &[]);
+        Failure::SameRevision.exit();
     }
+}
+
+/// Name a revision for messages, using the commit id if it has no name
+fn revision_label(rev: &Revision) -> String {
+    match (rev.name(), rev) {
+        (Some(name), _) => name.to_string(),
+        (None, Revision::Commit(c)) => c.id().to_string(),
+        (None, _) => "unknown revision".to_string(),
+    }
+}
+
+/// Check if a version is older than another
+///
+/// Only versions made of numbers separated by periods are compared.
+fn is_older(version: &str, than: &str) -> bool {
+    let parse = |v: &str| v.split('.').map(|n| n.parse::<u32>()).collect::<Result<Vec<u32>, _>>();
+    matches!((parse(version), parse(than)), (Ok(v), Ok(t)) if v < t)
+}
 
-    // Preliminaries out of the way, get the list of new upgrades.
-    let upgrades: Vec<String> = match get_upgrades(&repository, &from_branch, &to_branch) {
+/// Get the upgrades to include in the script, exiting with a message on error
+fn resolve_upgrades(builder: &UpgradeScriptBuilder<GitSource>) -> Vec<String> {
+    match builder.upgrades() {
         Ok(vec) => vec,
         Err(e) => {
-            eprintln!("{e}");
-            exit(1);
+            logging::error(&e.to_string(), &[]);
+            Failure::from(&e).exit();
         }
-    };
-
-    // Should we bail if upgrades.len() is 0?
-    if upgrades.len() == 0 {
-        eprintln!("No upgrades were found. Nothing to do.");
-        exit(1);
     }
+}
 
-    // Create the output file and begin doing the real work.
-    let mut outfile = match File::create(&out_path) {
-        Ok(f) => f,
+/// The list subcommand
+fn list(repository: &Repository, args: &BranchArgs) {
+    let (from_branch, to_branch) = resolve_branches(repository, args);
+    let builder = UpgradeScriptBuilder::new(repository, from_branch, to_branch);
+    print_lines(resolve_upgrades(&builder));
+}
+
+/// The diff subcommand
+fn diff(repository: &Repository, args: &BranchArgs) {
+    let (from_branch, to_branch) = resolve_branches(repository, args);
+    let diff = match get_upgrade_diff(repository, &from_branch, &to_branch) {
+        Ok(d) => d,
         Err(e) => {
-            eprintln!("{e}");
-            exit(1);
-        },
+            logging::error(&e.to_string(), &[]);
+            Failure::from(&e).exit();
+        }
     };
+    let added = diff.added.into_iter().map(|file| format!("A\t{file}"));
+    let removed = diff.removed.into_iter().map(|file| format!("D\t{file}"));
+    let changed = diff.changed.into_iter().map(|file| format!("M\t{file}"));
+    print_lines(added.chain(removed).chain(changed));
+}
 
-    match cli.prepend_file {
-        Some(v) => {
-            writeln!(&mut outfile, "-- Start of prepended code").expect("Unable to write to output");
-            for file in v {
-                match write_file(&mut outfile, &file) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        eprintln!("Error prepending file {}: {}", &file, e);
-                        exit(1);
-                    }
-                }
-            }
-            writeln!(&mut outfile, "-- End of prepended code\n").expect("Unable to write to output");
-        },
-        None => (),
+/// The versions subcommand
+fn versions(repository: &Repository, args: &BranchArgs) {
+    let (from_branch, to_branch) = resolve_branches(repository, args);
+    let (from_version, version) = resolve_versions(args, &from_branch, &to_branch);
+    print_lines([format!("from: {from_version}"), format!("to: {version}")]);
+}
+
+/// Print lines to standard output, exiting with a message on error
+///
+/// println! panics if standard output cannot be written, such as
+/// when it is piped to a program that has already exited.
+fn print_lines<I: IntoIterator<Item = String>>(lines: I) {
+    let mut out = io::stdout().lock();
+    let printed = lines.into_iter().try_for_each(|line| writeln!(out, "{line}")).and_then(|_| out.flush());
+    if let Err(e) = printed {
+        logging::error(&format!("Error writing to standard output: {}", e), &[]);
+        Failure::Output.exit();
     }
+}
+
+/// The generate subcommand
+fn generate(repository: &Repository, args: GenerateArgs) {
+    let (from_branch, to_branch) = resolve_branches(repository, &args.branches);
+    let cli = args.options;
 
-    // Write our preamble.
-    writeln!(&mut outfile, "-- Upgrade script for Evergreen {from_version} to {version}")
-        .expect("Unable to write to output");
-    writeln!(&mut outfile, "\\set eg_version '''{version}'''").expect("Unable to write to output");
-    writeln!(&mut outfile, "\nBEGIN;").expect("Unable to write to output");
+    // With --list-only, the skipped upgrades are filtered out and the
+    // rest are printed without doing anything else.
+    if cli.list_only {
+        let builder = UpgradeScriptBuilder::new(repository, from_branch, to_branch)
+            .skipped(cli.skipped.unwrap_or_default());
+        print_lines(resolve_upgrades(&builder));
+        return;
+    }
 
-    // Set up to handle upgrades that need to be moved.
-    let movedre: Option<Regex> = match cli.moved {
-        Some(v) => Some(make_or_regex(&v)),
-        None => None,
+    let (from_version, version) = resolve_versions(&args.branches, &from_branch, &to_branch);
+    check_distinct(&from_branch, &to_branch);
+    // Describe the revisions in case there is nothing to upgrade.
+    let from_label = format!("{} ({})", revision_label(&from_branch), from_version);
+    let to_label = format!("{} ({})", revision_label(&to_branch), version);
+    let builder = UpgradeScriptBuilder::new(repository, from_branch, to_branch)
+        .from_version(&from_version)
+        .version(&version)
+        .moved(cli.moved.unwrap_or_default())
+        .move_regex(cli.move_regex)
+        .strip_moved(cli.strip_moved)
+        .allow_empty(cli.allow_empty)
+        .skipped(cli.skipped.unwrap_or_default())
+        .prepend_files(cli.prepend_file.unwrap_or_default())
+        .prepend_position(cli.prepend_position.into())
+        .append_files(cli.append_file.unwrap_or_default())
+        .jobs(cli.jobs.unwrap_or(0))
+        .parallel_threshold(cli.parallel_threshold);
+
+    // Filename for the database upgrade script.
+    let prefix = match cli.prefix {
+        Some(p) => {
+            let separator = match cli.prefix_separator {
+                Some(s) => s,
+                None => {
+                    if !p.ends_with(['-', '_', '.']) {
+                        logging::warn(&format!("Prefix {} does not end with -, _, or .", p),
+                                      &[("prefix", &p)]);
+                    }
+                    String::new()
+                },
+            };
+            format!("{}{}", p, separator)
+        },
+        None => String::new(),
     };
-    let mut moved: Vec<String> = Vec::new();
+    let output = OutputOptions {
+        directory: cli.output_directory,
+        prefix,
+        clobber: cli.clobber,
+        ..Default::default()
+    };
+    // We're going to use out_path for opening and writing the file.
+    let out_path = output.path(&from_version, &version);
+    // A FIFO is meant to be written to, so it is not clobbered.
+    let fifo = is_fifo(&out_path);
+    // Fail fast rather than after generating the script. Creating the
+    // file checks again, in case something else creates it meanwhile.
+    if out_path.exists() && ! fifo && ! output.clobber {
+        output_exists(&out_path);
+    }
 
-    // Set up to handle upgrades that need to be skipped.
-    let skippedre: Option<Regex> = match cli.skipped {
-        Some(v) => Some(make_or_regex(&v)),
-        None => None,
+    // Formatting and test runs need the whole script, so it is then
+    // rendered before touching the output file, so that nothing is
+    // written if there is a problem. Otherwise it is written as it is
+    // generated. Opening a FIFO waits until something opens it for
+    // reading. Anything else is written to a temporary file and
+    // renamed, so a failure never leaves part of a script behind.
+    let labels = (from_label.as_ref(), to_label.as_ref());
+    let versions = (from_version.as_ref(), version.as_ref());
+    let script = if cli.format_with.is_some() || cli.test_run.is_some() {
+        let mut script: Vec<u8> = Vec::new();
+        let stats = builder.build_to(&mut script).unwrap_or_else(|e| generation_failed(e, labels, versions));
+        warn_if_empty(&stats, labels, versions);
+        if let Some(command) = &cli.format_with {
+            script = match pipe_script(command, &script) {
+                Ok(s) => s,
+                Err(e) => {
+                    logging::error(&format!("Error formatting upgrade script with {}: {}", command, e),
+                                   &[("command", command)]);
+                    Failure::from(&e).exit();
+                },
+            };
+        }
+        let written = if fifo {
+            OpenOptions::new().write(true).open(&out_path).and_then(|mut f| f.write_all(&script))
+        } else {
+            output.write_script(&out_path, &script)
+        };
+        if let Err(e) = written {
+            write_failed(&out_path, e);
+        }
+        log_written(&out_path, &stats, script.len() as u64);
+        Some(script)
+    } else {
+        let written = if fifo {
+            OpenOptions::new().write(true).open(&out_path).map_err(MkdbError::from).and_then(|f| {
+                let mut out = io::BufWriter::new(f);
+                let stats = builder.build_to(&mut out)?;
+                out.flush()?;
+                Ok(stats)
+            })
+        } else {
+            output.write_script_with(&out_path, |mut out| builder.build_to(&mut out))
+        };
+        let stats = match written {
+            Ok(s) => s,
+            Err(MkdbError::Io(e)) => write_failed(&out_path, e),
+            Err(e) => generation_failed(e, labels, versions),
+        };
+        warn_if_empty(&stats, labels, versions);
+        log_written(&out_path, &stats, stats.bytes_written);
+        None
     };
+    let path = out_path.display().to_string();
 
-    for file in upgrades {
-        match skippedre {
-            Some(ref re) => {
-                if re.is_match(&file) {
-                    continue;
-                }
+    #[cfg(feature = "clipboard")]
+    if cli.copy_path {
+        copy_path(&out_path);
+    }
+
+    if cli.reveal {
+        reveal(&out_path);
+    }
+
+    if let (Some(conninfo), Some(script)) = (&cli.test_run, &script) {
+        match test_run_script(conninfo, &String::from_utf8_lossy(script)) {
+            Ok(_) => logging::info(&format!("{} applied cleanly in a test run", path), &[("path", &path)]),
+            Err(e) => {
+                logging::error(&format!("{}: {}", path, e), &[("path", &path)]);
+                Failure::from(&e).exit();
             },
-            None => (),
         }
-        match movedre {
-            Some(ref re) => {
-                if re.is_match(&file) {
-                    moved.push(file.clone());
-                    continue;
-                }
+    }
+
+    // What was written to a FIFO is gone, so there is nothing to
+    // review.
+    if fifo && (cli.review || cli.pager) {
+        logging::warn(&format!("{} is a FIFO, so it cannot be reviewed", path), &[("path", &path)]);
+    } else if cli.review {
+        match review_file(&out_path) {
+            Ok(_) => (),
+            Err(e) => {
+                logging::error(&e.to_string(), &[]);
+                Failure::from(&e).exit();
             },
-            None => (),
         }
-        match write_upgrade(&mut outfile, &file) {
+        if cli.validate_after_edit {
+            validate_output(&out_path);
+        }
+    }
+
+    if cli.pager && ! fifo {
+        match page_file(&out_path) {
             Ok(_) => (),
             Err(e) => {
-                eprintln!("Error writing upgrade {}: {}", &file, e);
-                exit(1);
-            }
+                logging::error(&e.to_string(), &[]);
+                Failure::from(&e).exit();
+            },
         }
     }
-    writeln!(&mut outfile, "COMMIT;\n").expect("Unable to write to output");
-    if moved.len() > 0 {
-        writeln!(&mut outfile, "-- Start of moved upgrades").expect("Unable to write to output");
-        for file in moved {
-            match write_file(&mut outfile, &file) {
-                Ok(_) => (),
-                Err(e) => {
-                    eprintln!("Error writing moved upgrade {}: {}", &file, e);
-                    exit(1);
-                }
-            }
+
+    if let Some(command) = &cli.post_hook {
+        match run_hook(command, &out_path) {
+            Ok(_) => logging::info(&format!("Post-generation hook {} succeeded", command), &[("command", command)]),
+            Err(e) => {
+                logging::error(&format!("Post-generation hook failed: {}", e), &[("command", command)]);
+                Failure::Hook.exit();
+            },
         }
-        writeln!(&mut outfile, "-- End of moved upgrades\n").expect("Unable to write to output");
     }
+}
 
-    // Write code to update the auditor tables
-    writeln!(&mut outfile, "-- Update auditor tables to catch changes in source tables.").expect("Unable to write to output");
-    writeln!(&mut outfile, "-- Can be removed/skipped if there were no schema changes.").expect("Unable to write to output");
-    writeln!(&mut outfile, "SELECT auditor.update_auditors();").expect("Unable to write to output");
-
-    match cli.append_file {
-        Some(v) => {
-            writeln!(&mut outfile, "\n-- Start of appended code").expect("Unable to write to output");
-            for file in v {
-                match write_file(&mut outfile, &file) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        eprintln!("Error appending file {}: {}", &file, e);
-                        exit(1);
-                    }
+/// Exit with a message because the output file exists
+fn output_exists(path: &Path) -> ! {
+    let path = path.display().to_string();
+    logging::error(&format!("Output file {} exists, exiting", path), &[("path", &path)]);
+    logging::info("You can overwrite it with the -C option", &[]);
+    Failure::OutputExists.exit();
+}
+
+/// Report an error writing the output file and exit
+fn write_failed(path: &Path, e: io::Error) -> ! {
+    if e.kind() == io::ErrorKind::AlreadyExists {
+        output_exists(path);
+    }
+    let path = path.display().to_string();
+    logging::error(&format!("Error writing {}: {}", path, e), &[("path", &path)]);
+    Failure::Io.exit();
+}
+
+/// Report an error generating the upgrade script and exit
+///
+/// labels and versions are those upgraded from and to.
+fn generation_failed(e: MkdbError, labels: (&str, &str), versions: (&str, &str)) -> ! {
+    let (from_label, to_label) = labels;
+    let (from_version, version) = versions;
+    match e {
+        MkdbError::NoUpgrades => {
+            logging::error(&format!("No upgrades were found from {} to {}. Nothing to do.", from_label, to_label),
+                           &[("from_version", from_version), ("version", version)]);
+            if is_older(version, from_version) {
+                logging::info("The from and to branches may be swapped. Check out the newer branch and give the older one with -f", &[]);
+            } else {
+                logging::info("Check that the branch being upgraded to is checked out and -f gives the one upgraded from", &[]);
+            }
+            logging::info("Use --allow-empty to write a script without upgrades anyway", &[]);
+            Failure::NoUpgrades.exit();
+        },
+        MkdbError::Files(errors) => {
+            for e in &errors {
+                match e {
+                    MkdbError::File { path, source } =>
+                        logging::error(&format!("Unable to read {}: {}", path, source), &[("path", path)]),
+                    _ => logging::error(&e.to_string(), &[]),
                 }
             }
-            writeln!(&mut outfile, "-- End of appended code").expect("Unable to write to output");
+            Failure::Io.exit();
+        },
+        e => {
+            logging::error(&format!("Error generating upgrade script: {}", e), &[]);
+            Failure::from(&e).exit();
         },
-        None => (),
     }
+}
 
-    // Make sure that the output is written before we might open it in
-    // the editor.
-    drop(outfile);
+/// Warn when a script with no upgrades in it is written
+fn warn_if_empty(stats: &GenerationStats, labels: (&str, &str), versions: (&str, &str)) {
+    if stats.inlined == 0 && stats.moved == 0 {
+        logging::warn(&format!("No upgrades were found from {} to {}. Writing a script without any.", labels.0, labels.1),
+                      &[("from_version", versions.0), ("version", versions.1)]);
+    }
+}
 
-    if cli.review {
-        match review_file(&out_path.display().to_string()) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("{e}");
-                exit(1);
-            },
-        }
+/// Log that the output file was written
+fn log_written(path: &Path, stats: &GenerationStats, bytes: u64) {
+    let path = path.display().to_string();
+    logging::info(&format!("Wrote {} with {} upgrades and {} moved upgrades ({} bytes)",
+                           path, stats.inlined, stats.moved, bytes),
+                  &[("path", &path),
+                    ("inlined", &stats.inlined.to_string()),
+                    ("moved", &stats.moved.to_string()),
+                    ("bytes", &bytes.to_string())]);
+}
+
+/// Copy the absolute path of a file to the clipboard
+///
+/// Only warns if it cannot be done, such as when there is no display.
+#[cfg(feature = "clipboard")]
+fn copy_path(path: &Path) {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    // The clipboard holds text, so a path that is not valid UTF-8
+    // would be mangled.
+    let Some(path) = path.to_str() else {
+        let path = path.display().to_string();
+        logging::warn(&format!("Unable to copy {} to the clipboard: it is not valid UTF-8", path), &[("path", &path)]);
+        return;
+    };
+    if let Err(e) = arboard::Clipboard::new().and_then(|mut c| c.set_text(path)) {
+        logging::warn(&format!("Unable to copy {} to the clipboard: {}", path, e), &[("path", path)]);
+    }
+}
+
+/// Program that opens a directory in the system file manager
+#[cfg(target_os = "macos")]
+const FILE_MANAGER: &str = "open";
+#[cfg(windows)]
+const FILE_MANAGER: &str = "explorer";
+#[cfg(not(any(target_os = "macos", windows)))]
+const FILE_MANAGER: &str = "xdg-open";
+
+/// Open the directory containing a file in the system file manager
+///
+/// Only warns if it cannot be done, such as when there is no desktop.
+fn reveal(path: &Path) {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir_path = path.parent().unwrap_or(&path);
+    let dir = dir_path.display().to_string();
+    match Command::new(FILE_MANAGER).arg(dir_path).status() {
+        // Explorer's exit status does not mean anything.
+        Ok(status) if status.success() || cfg!(windows) => (),
+        Ok(status) => logging::warn(&format!("Unable to open {}: {} exited with {}", dir, FILE_MANAGER, status),
+                                    &[("path", &dir)]),
+        Err(e) => logging::warn(&format!("Unable to open {}: {}: {}", dir, FILE_MANAGER, e),
+                                &[("path", &dir)]),
+    }
+}
+
+/// Check if a path is a FIFO (named pipe)
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    path.metadata().is_ok_and(|m| m.file_type().is_fifo())
+}
+
+/// Check if a path is a FIFO (named pipe)
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+/// Warn about any problems in the script after it has been edited
+fn validate_output(path: &Path) {
+    let name = path.display().to_string();
+    let script = match read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            logging::error(&format!("Error reading {} after review: {}", name, e), &[("path", &name)]);
+            Failure::Io.exit();
+        },
+    };
+    for problem in validate_script(&script) {
+        logging::warn(&format!("{}: {}", name, problem), &[("path", &name), ("problem", &problem)]);
     }
 }
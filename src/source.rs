@@ -0,0 +1,137 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use crate::*;
+#[cfg(feature = "git")]
+use git2::Repository;
+
+/// Somewhere that Evergreen database upgrades can be read from
+///
+/// An upgrade source holds the upgrade files for a number of
+/// references, such as git revisions or source directories. The
+/// UpgradeScriptBuilder uses a source to find the new upgrades
+/// between two references and to read their contents.
+///
+/// Implement this to generate scripts from something other than git
+/// or the file system, or to supply canned upgrades in tests.
+pub trait UpgradeSource {
+    /// The type used to refer to one set of upgrades
+    type Ref;
+
+    /// Get the upgrades at a reference
+    ///
+    /// Returns an UpgradeEntry for each upgrade file, in the order
+    /// that they should be applied, on success.
+    fn upgrades(&self, rev: &Self::Ref) -> Result<Vec<UpgradeEntry>, MkdbError>;
+
+    /// Get the paths of the upgrades at "to" that are not at "from"
+    ///
+    /// Returns the paths in the order that the upgrades appear at
+    /// "to". The default compares the two listings from upgrades.
+    /// Override it if the source can compare them more cheaply.
+    fn new_upgrades(&self, from: &Self::Ref, to: &Self::Ref) -> Result<Vec<String>, MkdbError> {
+        Ok(new_upgrades(&self.upgrades(from)?, &self.upgrades(to)?))
+    }
+
+    /// Get the Evergreen version at a reference
+    ///
+    /// Returns MkdbError::Version if the version cannot be
+    /// determined.
+    fn version(&self, rev: &Self::Ref) -> Result<String, MkdbError>;
+
+    /// Get the path to read an upgrade file from
+    ///
+    /// The path is one returned by upgrades for the same reference.
+    fn file_path(&self, rev: &Self::Ref, path: &str) -> String;
+}
+
+/// Upgrades in the revisions of a git repository
+///
+/// Upgrade files are read from the repository's working directory, so
+/// the revision that they are read for should be checked out.
+#[cfg(feature = "git")]
+pub struct GitSource<'repo> {
+    repo: &'repo Repository,
+}
+
+#[cfg(feature = "git")]
+impl<'repo> GitSource<'repo> {
+    /// Create a source for a repository
+    pub fn new(repo: &'repo Repository) -> Self {
+        GitSource { repo }
+    }
+}
+
+#[cfg(feature = "git")]
+impl<'repo> UpgradeSource for GitSource<'repo> {
+    type Ref = Revision<'repo>;
+
+    fn upgrades(&self, rev: &Revision<'repo>) -> Result<Vec<UpgradeEntry>, MkdbError> {
+        get_branch_upgrades(self.repo, rev)
+    }
+
+    fn new_upgrades(&self, from: &Revision<'repo>, to: &Revision<'repo>) -> Result<Vec<String>, MkdbError> {
+        get_upgrades(self.repo, from, to)
+    }
+
+    fn version(&self, rev: &Revision<'repo>) -> Result<String, MkdbError> {
+        get_revision_version(rev).ok_or_else(|| {
+            let name = match rev {
+                Revision::Commit(c) => c.id().to_string(),
+                _ => rev.name().unwrap_or("unknown revision").to_string(),
+            };
+            MkdbError::Version(name)
+        })
+    }
+
+    fn file_path(&self, _rev: &Revision<'repo>, path: &str) -> String {
+        match self.repo.workdir() {
+            Some(dir) => dir.join(path).to_string_lossy().into_owned(),
+            None => path.to_string(),
+        }
+    }
+}
+
+/// Upgrades in Evergreen source directories
+///
+/// Each reference is the path of the top of an Evergreen source tree.
+/// Versions are determined from the last component of the path, so a
+/// directory named rel_3_14_0 is version 3.14.0.
+#[cfg(feature = "fs")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirSource;
+
+#[cfg(feature = "fs")]
+impl UpgradeSource for DirSource {
+    type Ref = PathBuf;
+
+    fn upgrades(&self, rev: &PathBuf) -> Result<Vec<UpgradeEntry>, MkdbError> {
+        get_dir_upgrades(rev)
+    }
+
+    fn version(&self, rev: &PathBuf) -> Result<String, MkdbError> {
+        rev.file_name()
+            .and_then(|n| get_name_version(&n.to_string_lossy()))
+            .ok_or_else(|| MkdbError::Version(rev.display().to_string()))
+    }
+
+    fn file_path(&self, rev: &PathBuf, path: &str) -> String {
+        rev.join(path).to_string_lossy().into_owned()
+    }
+}
@@ -0,0 +1,315 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+#![cfg(feature = "git")]
+mod common;
+
+use common::{FROM_BRANCH, Fixture, TO_BRANCH};
+use std::fs::{create_dir, write};
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Run mkdbupgrade in an empty directory with some environment
+fn run(args: &[&str], env: &[(&str, &str)]) -> Output {
+    let dir = tempfile::tempdir().unwrap();
+    run_in(dir.path(), args, env)
+}
+
+/// Run mkdbupgrade in a directory with some environment
+fn run_in(dir: &Path, args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mkdbupgrade"))
+        .args(args)
+        .current_dir(dir)
+        .env_remove("NO_COLOR")
+        .envs(env.iter().copied())
+        .output()
+        .unwrap()
+}
+
+fn has_escapes(output: &Output) -> bool {
+    output.stdout.contains(&0x1b) || output.stderr.contains(&0x1b)
+}
+
+#[test]
+fn color_can_be_forced() {
+    let output = run(&["--bogus"], &[("CLICOLOR_FORCE", "1")]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(has_escapes(&output));
+}
+
+#[test]
+fn no_color_wins() {
+    // Asking for color with --color=always bypasses the checks that
+    // clap makes itself, so only NO_COLOR keeps it off.
+    for args in [&["--bogus"][..], &["--help"], &[], &["--color=always", "--bogus"], &["--color", "always", "--help"]] {
+        let output = run(args, &[("CLICOLOR_FORCE", "1"), ("NO_COLOR", "1")]);
+        assert!(!has_escapes(&output), "{args:?}");
+    }
+
+    // The prefix warning is logged in color when it is asked for.
+    let fixture = Fixture::new();
+    let out = tempfile::tempdir().unwrap();
+    let args = ["-f", FROM_BRANCH, "-C", "-O", out.path().to_str().unwrap(), "-P", "cwmars", "--color=always"];
+    let output = run_in(fixture.path(), &args, &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("does not end with"));
+    assert!(has_escapes(&output));
+    let output = run_in(fixture.path(), &args, &[("NO_COLOR", "1")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("does not end with"));
+    assert!(!has_escapes(&output));
+}
+
+#[cfg(feature = "expand")]
+#[test]
+fn paths_are_expanded() {
+    let fixture = Fixture::new();
+    let home = tempfile::tempdir().unwrap();
+    let home_dir = home.path().to_str().unwrap();
+    create_dir(home.path().join("out")).unwrap();
+    write(home.path().join("pre.sql"), "-- prepended from home\n").unwrap();
+
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-O", "~/out", "-p", "$HOME/pre.sql"],
+                        &[("HOME", home_dir)]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let script = std::fs::read_to_string(home.path().join("out/3.14.0-3.14.1-upgrade-db.sql")).unwrap();
+    assert!(script.starts_with("-- Start of prepended code\n-- prepended from home\n"));
+
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-C", "-O", "${MKDB_TEST_DIR}/out"],
+                        &[("MKDB_TEST_DIR", home_dir)]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[cfg(feature = "expand")]
+#[test]
+fn unset_variable_is_usage_error() {
+    let output = run(&["-f", FROM_BRANCH, "-O", "$MKDB_TEST_UNSET/out"], &[]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[cfg(unix)]
+#[test]
+fn writes_to_fifo() {
+    let fixture = Fixture::new();
+    let out = tempfile::tempdir().unwrap();
+    let fifo = out.path().join("3.14.0-3.14.1-upgrade-db.sql");
+    assert!(Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+    let reader = std::thread::spawn(move || std::fs::read_to_string(fifo).unwrap());
+    // No -C, since a FIFO is not clobbered.
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-O", out.path().to_str().unwrap()], &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(reader.join().unwrap().starts_with("-- Upgrade script for Evergreen 3.14.0 to 3.14.1\n"));
+}
+
+// Without a display there is no clipboard on X11 or Wayland.
+#[cfg(all(unix, not(target_os = "macos"), feature = "clipboard"))]
+#[test]
+fn copy_path_without_clipboard_warns() {
+    let fixture = Fixture::new();
+    let out = tempfile::tempdir().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_mkdbupgrade"))
+        .args(["-f", FROM_BRANCH, "-O", out.path().to_str().unwrap(), "--copy-path"])
+        .current_dir(fixture.path())
+        .env_remove("DISPLAY")
+        .env_remove("WAYLAND_DISPLAY")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unable to copy"));
+    assert!(out.path().join("3.14.0-3.14.1-upgrade-db.sql").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn reveal_without_file_manager_warns() {
+    let fixture = Fixture::new();
+    let out = tempfile::tempdir().unwrap();
+    let empty = tempfile::tempdir().unwrap();
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-O", out.path().to_str().unwrap(), "--reveal"],
+                        &[("PATH", empty.path().to_str().unwrap())]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unable to open"));
+}
+
+#[test]
+fn jobs_do_not_change_the_script() {
+    let fixture = Fixture::new();
+    let out = tempfile::tempdir().unwrap();
+    let out_dir = out.path().to_str().unwrap();
+    let mut scripts: Vec<String> = Vec::new();
+    for jobs in ["1", "4"] {
+        let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-C", "-O", out_dir, "-j", jobs], &[]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        scripts.push(std::fs::read_to_string(out.path().join("3.14.0-3.14.1-upgrade-db.sql")).unwrap());
+    }
+    assert_eq!(scripts[0], scripts[1]);
+}
+
+#[test]
+fn same_revision_is_reported() {
+    let fixture = Fixture::new();
+    let output = run_in(fixture.path(), &["-f", TO_BRANCH], &[]);
+    assert_eq!(output.status.code(), Some(12));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("rel_3_14_1 and the current branch rel_3_14_1 are the same commit"), "{stderr}");
+}
+
+#[test]
+fn swapped_branches_are_suggested() {
+    let fixture = Fixture::with_upgrades(&[("1440.data.example.sql", "SELECT 1440;\n")],
+                                         &[("1440.data.example.sql", "SELECT 1440;\n"),
+                                           ("1441.schema.example.sql", "SELECT 1441;\n")]);
+    fixture.repo.set_head(&format!("refs/heads/{FROM_BRANCH}")).unwrap();
+    let output = run_in(fixture.path(), &["-f", TO_BRANCH], &[]);
+    assert_eq!(output.status.code(), Some(7));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("No upgrades were found from rel_3_14_1 (3.14.1) to rel_3_14_0 (3.14.0)"), "{stderr}");
+    assert!(stderr.contains("may be swapped"), "{stderr}");
+}
+
+#[test]
+fn existing_output_is_kept() {
+    let fixture = Fixture::new();
+    let out = tempfile::tempdir().unwrap();
+    let out_dir = out.path().to_str().unwrap();
+    let path = out.path().join("3.14.0-3.14.1-upgrade-db.sql");
+    write(&path, "-- keep me\n").unwrap();
+    // The formatter would leave a marker if the script were generated.
+    let marker = fixture.path().join("formatted");
+    let format_with = format!("touch {}", marker.to_str().unwrap());
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-O", out_dir, "--format-with", &format_with], &[]);
+    assert_eq!(output.status.code(), Some(8));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("exists"));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "-- keep me\n");
+    assert!(!marker.exists());
+
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-C", "-O", out_dir], &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(std::fs::read_to_string(&path).unwrap().starts_with("-- Upgrade script for Evergreen 3.14.0 to 3.14.1\n"));
+}
+
+#[test]
+fn prepend_position_is_checked() {
+    let output = run(&["-f", FROM_BRANCH, "--prepend-position", "after-commit"], &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("inside-transaction"));
+}
+
+#[test]
+fn empty_upgrades_are_allowed() {
+    let upgrades = [("1440.data.example.sql", "SELECT 1440;\n")];
+    let fixture = Fixture::with_upgrades(&upgrades, &upgrades);
+    let out = tempfile::tempdir().unwrap();
+    let out_dir = out.path().to_str().unwrap();
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-O", out_dir], &[]);
+    assert_eq!(output.status.code(), Some(7));
+    assert!(!out.path().join("3.14.0-3.14.1-upgrade-db.sql").exists());
+
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-O", out_dir, "--allow-empty"], &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let script = std::fs::read_to_string(out.path().join("3.14.0-3.14.1-upgrade-db.sql")).unwrap();
+    assert!(script.contains("\nBEGIN;\nCOMMIT;\n"), "{script}");
+    assert!(!script.contains("1440"));
+}
+
+#[test]
+fn missing_upgrade_leaves_no_output() {
+    let fixture = Fixture::new();
+    // The upgrade is in git but missing from the working directory.
+    std::fs::remove_file(fixture.path().join(common::UPGRADE_DIR).join("1441.schema.example.sql")).unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let out_dir = out.path().to_str().unwrap();
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-O", out_dir], &[]);
+    assert_eq!(output.status.code(), Some(9));
+    assert_eq!(std::fs::read_dir(out.path()).unwrap().count(), 0);
+
+    // An existing script is left as it was.
+    let path = out.path().join("3.14.0-3.14.1-upgrade-db.sql");
+    write(&path, "-- keep me\n").unwrap();
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-C", "-O", out_dir], &[]);
+    assert_eq!(output.status.code(), Some(9));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "-- keep me\n");
+    assert_eq!(std::fs::read_dir(out.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn missing_input_files_leave_no_output() {
+    let fixture = Fixture::new();
+    let out = tempfile::tempdir().unwrap();
+    let output = run_in(fixture.path(), &["-f", FROM_BRANCH, "-O", out.path().to_str().unwrap(),
+                                          "-p", "missing-pre.sql", "-a", "missing-post-1.sql", "-a", "missing-post-2.sql"], &[]);
+    assert_eq!(output.status.code(), Some(9));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for name in ["missing-pre.sql", "missing-post-1.sql", "missing-post-2.sql"] {
+        assert!(stderr.contains(name), "{stderr}");
+    }
+    assert_eq!(std::fs::read_dir(out.path()).unwrap().count(), 0);
+}
+
+// Linux and most other Unix systems allow any bytes but / and NUL in
+// a file name, though macOS requires valid UTF-8.
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn output_directory_need_not_be_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    let fixture = Fixture::new();
+    let out = tempfile::tempdir().unwrap();
+    let dir = out.path().join(OsStr::from_bytes(b"upgrades-\xff"));
+    create_dir(&dir).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_mkdbupgrade"))
+        .args(["-f", FROM_BRANCH, "--post-hook", "test -f", "-O"])
+        .arg(&dir)
+        .current_dir(fixture.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let script = std::fs::read_to_string(dir.join("3.14.0-3.14.1-upgrade-db.sql")).unwrap();
+    assert!(script.starts_with("-- Upgrade script for Evergreen 3.14.0 to 3.14.1\n"));
+}
+
+#[test]
+fn closed_stdout_is_reported() {
+    let fixture = Fixture::new();
+    for args in [&["list", "-f", FROM_BRANCH][..], &["diff", "-f", FROM_BRANCH], &["versions", "-f", FROM_BRANCH],
+                 &["-f", FROM_BRANCH, "--list-only"]] {
+        let (reader, writer) = std::io::pipe().unwrap();
+        drop(reader);
+        let output = Command::new(env!("CARGO_BIN_EXE_mkdbupgrade"))
+            .args(args)
+            .current_dir(fixture.path())
+            .stdout(writer)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(13), "{args:?}: {stderr}");
+        assert!(stderr.contains("Error writing to standard output"), "{args:?}: {stderr}");
+        assert!(!stderr.contains("panicked"), "{args:?}: {stderr}");
+    }
+}
+
+#[test]
+fn given_versions_are_normalized() {
+    let fixture = Fixture::new();
+    let output = run_in(fixture.path(), &["versions", "-f", FROM_BRANCH, "-F", "rel_3_14_0", "-v", "3_15_0"], &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "from: 3.14.0\nto: 3.15.0\n");
+    let output = run_in(fixture.path(), &["versions", "-f", FROM_BRANCH, "-v", "3.15-beta"], &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "from: 3.14.0\nto: 3.15-beta\n");
+}
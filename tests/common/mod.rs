@@ -0,0 +1,134 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Test fixture that builds a temporary Evergreen-like git repository
+#![allow(dead_code)]
+use git2::{Commit, Oid, Repository, Signature};
+use std::fs::{create_dir_all, write};
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Upgrade directory within the fixture repository
+pub const UPGRADE_DIR: &str = "Open-ILS/src/sql/Pg/upgrade";
+
+/// Name of the branch being upgraded from
+pub const FROM_BRANCH: &str = "rel_3_14_0";
+
+/// Name of the branch being upgraded to, which is checked out
+pub const TO_BRANCH: &str = "rel_3_14_1";
+
+/// Upgrades committed to the "from" branch
+pub const FROM_UPGRADES: &[(&str, &str)] = &[
+    ("1439.schema.dropped.sql", "BEGIN;\nDROP TABLE config.example;\nCOMMIT;\n"),
+    ("1440.data.example.sql", "BEGIN;\nINSERT INTO config.global_flag (name) VALUES ('example');\nCOMMIT;\n"),
+];
+
+/// Upgrades committed to the "to" branch
+pub const TO_UPGRADES: &[(&str, &str)] = &[
+    ("1440.data.example.sql", "BEGIN;\nINSERT INTO config.global_flag (name) VALUES ('changed');\nCOMMIT;\n"),
+    ("1441.schema.example.sql", "BEGIN;\nCREATE INDEX foo_idx ON actor.usr (id);\nCOMMIT;\n"),
+    ("1442.function.example.sql", "BEGIN;\nSELECT 1442;\nCOMMIT;\n"),
+];
+
+/// A temporary git repository with two Evergreen "release" branches
+///
+/// The repository is removed when this is dropped.
+pub struct Fixture {
+    pub dir: TempDir,
+    pub repo: Repository,
+}
+
+impl Fixture {
+    /// Build the repository with FROM_BRANCH and TO_BRANCH
+    ///
+    /// TO_BRANCH is checked out, and FROM_BRANCH is also tagged.
+    pub fn new() -> Fixture {
+        Fixture::with_upgrades(FROM_UPGRADES, TO_UPGRADES)
+    }
+
+    /// Build the repository with the given upgrades on each branch
+    ///
+    /// Each upgrade is a file name and its contents. Otherwise, this
+    /// is the same as Fixture::new.
+    pub fn with_upgrades(from_upgrades: &[(&str, &str)], to_upgrades: &[(&str, &str)]) -> Fixture {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let from = commit_upgrades(&repo, FROM_BRANCH, from_upgrades, &[], None);
+        repo.tag_lightweight(FROM_BRANCH, from.as_object(), false).unwrap();
+        let to = commit_upgrades(&repo, TO_BRANCH, to_upgrades, &[], Some(&from));
+        drop(from);
+        drop(to);
+        repo.set_head(&format!("refs/heads/{}", TO_BRANCH)).unwrap();
+        Fixture { dir, repo }
+    }
+
+    /// Path of the repository's working directory
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Commit upgrades and other files to a new branch from TO_BRANCH
+    ///
+    /// Upgrade names may include subdirectories of UPGRADE_DIR. Other
+    /// files are relative to the top of the repository. The files are
+    /// left in the working directory, but HEAD does not change.
+    pub fn commit_branch(&self, branch: &str, upgrades: &[(&str, &str)], files: &[(&str, &str)]) -> Oid {
+        let parent = self.repo.find_branch(TO_BRANCH, git2::BranchType::Local).unwrap().get().peel_to_commit().unwrap();
+        commit_upgrades(&self.repo, branch, upgrades, files, Some(&parent)).id()
+    }
+
+    /// Delete an object from the repository's object database
+    ///
+    /// Anything that tries to read the object afterward fails, which
+    /// shows whether it was read.
+    pub fn remove_object(&self, id: Oid) {
+        let hex = id.to_string();
+        std::fs::remove_file(self.repo.path().join("objects").join(&hex[..2]).join(&hex[2..])).unwrap();
+    }
+}
+
+/// Write upgrade and other files to the working directory and commit them
+///
+/// Any upgrades left from a previous commit are replaced, so the
+/// branch has exactly the upgrades and files given.
+fn commit_upgrades<'r>(repo: &'r Repository, branch: &str, upgrades: &[(&str, &str)], files: &[(&str, &str)],
+                       parent: Option<&Commit<'r>>) -> Commit<'r> {
+    let workdir = repo.workdir().unwrap().to_path_buf();
+    let upgrade_dir = workdir.join(UPGRADE_DIR);
+    if upgrade_dir.exists() {
+        std::fs::remove_dir_all(&upgrade_dir).unwrap();
+    }
+    create_dir_all(&upgrade_dir).unwrap();
+    let mut index = repo.index().unwrap();
+    index.clear().unwrap();
+    let upgrades = upgrades.iter().map(|(name, content)| (Path::new(UPGRADE_DIR).join(name), content));
+    let files = files.iter().map(|(name, content)| (Path::new(name).to_path_buf(), content));
+    for (path, content) in upgrades.chain(files) {
+        create_dir_all(workdir.join(&path).parent().unwrap()).unwrap();
+        write(workdir.join(&path), content).unwrap();
+        index.add_path(&path).unwrap();
+    }
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("Test", "test@example.com").unwrap();
+    let parents: Vec<&Commit> = parent.into_iter().collect();
+    let oid = repo.commit(Some(&format!("refs/heads/{}", branch)), &signature, &signature,
+                          branch, &tree, &parents).unwrap();
+    repo.find_commit(oid).unwrap()
+}
@@ -0,0 +1,48 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use mkdbupgrade::{HeadError, MkdbError};
+use std::io;
+
+#[test]
+fn io_error_converts() {
+    let e: MkdbError = io::Error::new(io::ErrorKind::NotFound, "gone").into();
+    assert!(matches!(e, MkdbError::Io(_)));
+    assert_eq!(e.to_string(), "gone");
+}
+
+#[cfg(feature = "git")]
+#[test]
+fn git_error_converts() {
+    let e: MkdbError = git2::Error::from_str("bad ref").into();
+    assert!(matches!(e, MkdbError::Git(_)));
+}
+
+#[test]
+fn head_error_converts() {
+    let e: MkdbError = HeadError { target: "abc1234".to_string(), detached: true }.into();
+    assert!(matches!(e, MkdbError::Head(_)));
+    assert_eq!(e.to_string(), "HEAD is detached at abc1234, not a branch");
+}
+
+#[test]
+fn head_error_names_reference() {
+    let e = HeadError { target: "refs/tags/rel_3_14_0".to_string(), detached: false };
+    assert_eq!(e.to_string(), "HEAD is refs/tags/rel_3_14_0, not a branch");
+}
@@ -0,0 +1,2 @@
+-- Prepended without a final newline
+SET search_path = evergreen, public;
\ No newline at end of file
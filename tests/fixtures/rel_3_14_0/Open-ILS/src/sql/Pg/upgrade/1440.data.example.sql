@@ -0,0 +1,7 @@
+BEGIN;
+
+SELECT evergreen.upgrade_deps_block_check('1440', :eg_version);
+
+INSERT INTO config.global_flag (name, label) VALUES ('example', 'Example');
+
+COMMIT;
@@ -0,0 +1,8 @@
+--Upgrade Script for 3.14.0 to 3.14.1
+BEGIN;
+
+SELECT evergreen.upgrade_deps_block_check('1441', :eg_version);
+
+CREATE INDEX foo_idx ON actor.usr (id);
+
+  COMMIT;
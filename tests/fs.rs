@@ -0,0 +1,82 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+#![cfg(feature = "fs")]
+use mkdbupgrade::{UpgradeScriptBuilder, get_dir_upgrades, new_upgrades};
+
+const FROM: &str = "tests/fixtures/rel_3_14_0";
+const TO: &str = "tests/fixtures/rel_3_14_1";
+
+#[test]
+fn dir_upgrades_match_git() {
+    let upgrades = get_dir_upgrades(TO).unwrap();
+    let paths: Vec<&str> = upgrades.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(paths, vec!["Open-ILS/src/sql/Pg/upgrade/1440.data.example.sql",
+                           "Open-ILS/src/sql/Pg/upgrade/1441.schema.example.sql"]);
+    // Same as `git hash-object tests/fixtures/1441.schema.example.sql`
+    assert_eq!(upgrades[1].oid, "8c53ef71c2b21b65b570713cdc25644d569c0e49");
+}
+
+#[test]
+fn dir_new_upgrades() {
+    let from = get_dir_upgrades(FROM).unwrap();
+    let to = get_dir_upgrades(TO).unwrap();
+    assert_eq!(new_upgrades(&from, &to), vec!["Open-ILS/src/sql/Pg/upgrade/1441.schema.example.sql"]);
+}
+
+#[test]
+fn builder_from_dirs() {
+    let script = UpgradeScriptBuilder::from_dirs(FROM, TO).render_script().unwrap();
+    assert!(script.starts_with("-- Upgrade script for Evergreen 3.14.0 to 3.14.1\n"));
+    assert!(script.contains("CREATE INDEX foo_idx ON actor.usr (id);"));
+    assert!(!script.contains("1440"));
+}
+
+#[test]
+fn builder_stats() {
+    let builder = UpgradeScriptBuilder::from_dirs(FROM, TO)
+        .prepend_files(vec!["tests/fixtures/1441.schema.example.sql".to_string()]);
+    let mut out: Vec<u8> = Vec::new();
+    let stats = builder.build_to(&mut out).unwrap();
+    assert_eq!(stats.from_version, "3.14.0");
+    assert_eq!(stats.version, "3.14.1");
+    assert_eq!(stats.inlined, 1);
+    assert_eq!(stats.moved, 0);
+    assert_eq!(stats.prepended, 1);
+    assert_eq!(stats.appended, 0);
+    assert_eq!(stats.bytes_written, out.len() as u64);
+}
+
+#[test]
+fn dir_upgrades_in_subdirectories() {
+    let root = tempfile::tempdir().unwrap();
+    let upgrade_dir = root.path().join(mkdbupgrade::UPGRADE_DIR);
+    std::fs::create_dir_all(upgrade_dir.join("archive/deep")).unwrap();
+    std::fs::write(upgrade_dir.join("1443.schema.example.sql"), "SELECT 1443;\n").unwrap();
+    std::fs::write(upgrade_dir.join("archive/1000.schema.old.sql"), "SELECT 1000;\n").unwrap();
+    std::fs::write(upgrade_dir.join("archive/deep/1001.data.old.sql"), "SELECT 1001;\n").unwrap();
+    let upgrades = get_dir_upgrades(root.path()).unwrap();
+    let paths: Vec<&str> = upgrades.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(paths, vec!["Open-ILS/src/sql/Pg/upgrade/1443.schema.example.sql",
+                           "Open-ILS/src/sql/Pg/upgrade/archive/1000.schema.old.sql",
+                           "Open-ILS/src/sql/Pg/upgrade/archive/deep/1001.data.old.sql"]);
+    for path in paths {
+        assert!(root.path().join(path).is_file(), "{path}");
+    }
+}
@@ -0,0 +1,235 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+#![cfg(feature = "git")]
+mod common;
+
+use common::{Fixture, FROM_BRANCH, TO_BRANCH};
+use mkdbupgrade::*;
+
+fn path(name: &str) -> String {
+    format!("{}/{}", UPGRADE_DIR, name)
+}
+
+#[test]
+fn current_branch_and_version() {
+    let fixture = Fixture::new();
+    let branch = get_current_branch(&fixture.repo).unwrap();
+    assert_eq!(branch.name().unwrap(), Some(TO_BRANCH));
+    assert_eq!(get_branch_version(&branch), Some("3.14.1".to_string()));
+}
+
+#[test]
+fn branch_upgrades() {
+    let fixture = Fixture::new();
+    let from = Revision::Branch(find_branch(&fixture.repo, FROM_BRANCH).unwrap());
+    let upgrades = get_branch_upgrades(&fixture.repo, &from).unwrap();
+    let paths: Vec<String> = upgrades.into_iter().map(|e| e.path).collect();
+    assert_eq!(paths, vec![path("1439.schema.dropped.sql"), path("1440.data.example.sql")]);
+}
+
+#[test]
+fn upgrades_between_branches() {
+    let fixture = Fixture::new();
+    let from = Revision::Branch(find_branch(&fixture.repo, FROM_BRANCH).unwrap());
+    let to = Revision::from(get_current_branch(&fixture.repo).unwrap());
+    assert_eq!(get_upgrades(&fixture.repo, &from, &to).unwrap(),
+               vec![path("1441.schema.example.sql"), path("1442.function.example.sql")]);
+}
+
+#[test]
+fn upgrade_diff() {
+    let fixture = Fixture::new();
+    let from = Revision::Branch(find_branch(&fixture.repo, FROM_BRANCH).unwrap());
+    let to = Revision::from(get_current_branch(&fixture.repo).unwrap());
+    let diff = get_upgrade_diff(&fixture.repo, &from, &to).unwrap();
+    assert_eq!(diff.added, vec![path("1441.schema.example.sql"), path("1442.function.example.sql")]);
+    assert_eq!(diff.removed, vec![path("1439.schema.dropped.sql")]);
+    assert_eq!(diff.changed, vec![path("1440.data.example.sql")]);
+}
+
+#[test]
+fn tag_and_commit_revisions() {
+    let fixture = Fixture::new();
+    let tag = Revision::Tag(find_tag(&fixture.repo, FROM_BRANCH).unwrap());
+    assert_eq!(get_revision_version(&tag), Some("3.14.0".to_string()));
+    let commit = Revision::Commit(find_commit(&fixture.repo, FROM_BRANCH).unwrap());
+    assert_eq!(get_revision_version(&commit), None);
+    let to = Revision::from(get_current_branch(&fixture.repo).unwrap());
+    assert_eq!(get_upgrades(&fixture.repo, &tag, &to).unwrap(),
+               get_upgrades(&fixture.repo, &commit, &to).unwrap());
+}
+
+#[test]
+fn missing_refs_are_git_errors() {
+    let fixture = Fixture::new();
+    assert!(matches!(find_branch(&fixture.repo, "rel_9_9_9"), Err(MkdbError::Git(_))));
+    assert!(matches!(find_tag(&fixture.repo, "rel_9_9_9"), Err(MkdbError::Git(_))));
+    assert!(matches!(find_commit(&fixture.repo, "0123456789"), Err(MkdbError::Git(_))));
+}
+
+#[test]
+fn detached_head_is_head_error() {
+    let fixture = Fixture::new();
+    let oid = fixture.repo.head().unwrap().target().unwrap();
+    fixture.repo.set_head_detached(oid).unwrap();
+    match get_current_branch(&fixture.repo) {
+        Err(MkdbError::Head(e)) => assert!(e.detached),
+        other => panic!("expected a HeadError, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn render_script_from_git() {
+    let fixture = Fixture::new();
+    let from = Revision::Branch(find_branch(&fixture.repo, FROM_BRANCH).unwrap());
+    let to = Revision::from(get_current_branch(&fixture.repo).unwrap());
+    let script = UpgradeScriptBuilder::new(&fixture.repo, from, to)
+        .moved(vec!["1442".to_string()])
+        .render_script()
+        .unwrap();
+    let expected = "\
+-- Upgrade script for Evergreen 3.14.0 to 3.14.1
+\\set eg_version '''3.14.1'''
+
+BEGIN;
+CREATE INDEX foo_idx ON actor.usr (id);
+COMMIT;
+
+-- Start of moved upgrades
+BEGIN;
+SELECT 1442;
+COMMIT;
+-- End of moved upgrades
+
+-- Update auditor tables to catch changes in source tables.
+-- Can be removed/skipped if there were no schema changes.
+SELECT auditor.update_auditors();
+";
+    assert_eq!(script, expected);
+}
+
+#[test]
+fn cached_listings() {
+    let fixture = Fixture::new();
+    let from = Revision::Branch(find_branch(&fixture.repo, FROM_BRANCH).unwrap());
+    let tag = Revision::Tag(find_tag(&fixture.repo, FROM_BRANCH).unwrap());
+    let to = Revision::from(get_current_branch(&fixture.repo).unwrap());
+    let first = get_branch_upgrades(&fixture.repo, &from).unwrap();
+    // The tag has the same upgrade tree, so it is served from the cache.
+    assert_eq!(get_branch_upgrades(&fixture.repo, &tag).unwrap(), first);
+    assert_eq!(get_upgrades(&fixture.repo, &from, &to).unwrap(), get_upgrades(&fixture.repo, &tag, &to).unwrap());
+    clear_upgrade_cache();
+    assert_eq!(get_branch_upgrades(&fixture.repo, &from).unwrap(), first);
+}
+
+#[test]
+fn listings_are_cached_per_repository() {
+    let first = Fixture::new();
+    let second = Fixture::new();
+    let upgrade_tree = |repo| {
+        let rev = Revision::Branch(find_branch(repo, FROM_BRANCH).unwrap());
+        rev.tree().unwrap().get_path(std::path::Path::new(UPGRADE_DIR)).unwrap().id()
+    };
+    let rev = Revision::Branch(find_branch(&first.repo, FROM_BRANCH).unwrap());
+    assert!(!get_branch_upgrades(&first.repo, &rev).unwrap().is_empty());
+    // The second repository has the same upgrade tree, but once it
+    // cannot be read there, the first repository's listing is not
+    // used instead.
+    let id = upgrade_tree(&second.repo);
+    assert_eq!(id, upgrade_tree(&first.repo));
+    second.remove_object(id);
+    let repo = git2::Repository::open(second.path()).unwrap();
+    let rev = Revision::Branch(find_branch(&repo, FROM_BRANCH).unwrap());
+    assert!(get_branch_upgrades(&repo, &rev).is_err());
+}
+
+#[test]
+fn only_the_upgrade_directory_is_read() {
+    let fixture = Fixture::new();
+    let commit = fixture.commit_branch("rel_3_14_2", &[("1443.schema.example.sql", "SELECT 1443;\n")],
+                                       &[("Open-ILS/web/js/example.js", "// example\n"), ("README", "Evergreen\n")]);
+    let root = fixture.repo.find_commit(commit).unwrap().tree().unwrap();
+    let web = root.get_path(std::path::Path::new("Open-ILS/web")).unwrap().id();
+    fixture.remove_object(web);
+    // Open the repository again so that nothing is cached.
+    let repo = git2::Repository::open(fixture.path()).unwrap();
+    // Walking the whole revision now fails, but listing its upgrades does not.
+    let root = repo.find_commit(commit).unwrap().tree().unwrap();
+    assert!(root.walk(git2::TreeWalkMode::PreOrder, |_, _| git2::TreeWalkResult::Ok).is_err());
+    clear_upgrade_cache();
+    let rev = Revision::Branch(find_branch(&repo, "rel_3_14_2").unwrap());
+    let paths: Vec<String> = get_branch_upgrades(&repo, &rev).unwrap().into_iter().map(|e| e.path).collect();
+    assert_eq!(paths, vec![path("1443.schema.example.sql")]);
+}
+
+#[test]
+fn shared_subdirectories_are_skipped() {
+    let fixture = Fixture::new();
+    let old: &[(&str, &str)] = &[
+        ("1443.schema.example.sql", "SELECT 1443;\n"),
+        ("archive/1000.schema.old.sql", "SELECT 1000;\n"),
+        ("archive/1001.data.old.sql", "SELECT 1001;\n"),
+        ("extra/1002.data.old.sql", "SELECT 1002;\n"),
+    ];
+    let mut new = old.to_vec();
+    new.push(("1444.schema.example.sql", "SELECT 1444;\n"));
+    new.push(("extra/1445.data.example.sql", "SELECT 1445;\n"));
+    let commit = fixture.commit_branch("rel_3_14_2", old, &[]);
+    fixture.commit_branch("rel_3_14_3", &new, &[]);
+    let root = fixture.repo.find_commit(commit).unwrap().tree().unwrap();
+    let archive = root.get_path(&std::path::Path::new(UPGRADE_DIR).join("archive")).unwrap().id();
+    fixture.remove_object(archive);
+
+    // The archive directory is the same in both, so it is never read.
+    let repo = git2::Repository::open(fixture.path()).unwrap();
+    let from = Revision::Branch(find_branch(&repo, "rel_3_14_2").unwrap());
+    let to = Revision::Branch(find_branch(&repo, "rel_3_14_3").unwrap());
+    assert_eq!(get_upgrades(&repo, &from, &to).unwrap(),
+               vec![path("1444.schema.example.sql"), path("extra/1445.data.example.sql")]);
+    assert!(get_upgrades(&repo, &from, &from).unwrap().is_empty());
+    assert!(get_upgrades(&repo, &to, &from).unwrap().is_empty());
+}
+
+#[test]
+fn nested_upgrade_paths() {
+    let fixture = Fixture::new();
+    fixture.commit_branch("rel_3_14_2", &[
+        ("1443.schema.example.sql", "BEGIN;\nSELECT 1443;\nCOMMIT;\n"),
+        ("archive/1000.schema.old.sql", "BEGIN;\nSELECT 1000;\nCOMMIT;\n"),
+        ("archive/deep/1001.data.old.sql", "BEGIN;\nSELECT 1001;\nCOMMIT;\n"),
+    ], &[]);
+    let rev = Revision::Branch(find_branch(&fixture.repo, "rel_3_14_2").unwrap());
+    let paths: Vec<String> = get_branch_upgrades(&fixture.repo, &rev).unwrap().into_iter().map(|e| e.path).collect();
+    assert_eq!(paths, vec![path("1443.schema.example.sql"), path("archive/1000.schema.old.sql"),
+                           path("archive/deep/1001.data.old.sql")]);
+    for p in &paths {
+        assert!(fixture.path().join(p).is_file(), "{p}");
+    }
+
+    // Listing and comparing the trees find the same new upgrades.
+    let from = Revision::Branch(find_branch(&fixture.repo, TO_BRANCH).unwrap());
+    let listed = new_upgrades(&get_branch_upgrades(&fixture.repo, &from).unwrap(),
+                              &get_branch_upgrades(&fixture.repo, &rev).unwrap());
+    assert_eq!(get_upgrades(&fixture.repo, &from, &rev).unwrap(), listed);
+
+    let script = UpgradeScriptBuilder::new(&fixture.repo, from, rev).render_script().unwrap();
+    assert!(script.contains("SELECT 1000;\n"));
+    assert!(script.contains("SELECT 1001;\n"));
+}
@@ -0,0 +1,76 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use mkdbupgrade::{MkdbError, build_literal_matcher, build_move_matcher};
+
+const DIR: &str = "Open-ILS/src/sql/Pg/upgrade";
+
+fn patterns(list: &[&str]) -> Vec<String> {
+    list.iter().map(|p| p.to_string()).collect()
+}
+
+#[test]
+fn matches_any_pattern() {
+    let matcher = build_move_matcher(&patterns(&["1440", "function", r"^.*/1450\."])).unwrap();
+    assert!(matcher.matches(&format!("{DIR}/1440.data.example.sql")));
+    assert!(matcher.matches(&format!("{DIR}/1442.function.example.sql")));
+    assert!(matcher.matches(&format!("{DIR}/1450.schema.example.sql")));
+    assert!(!matcher.matches(&format!("{DIR}/1441.schema.example.sql")));
+    assert!(!matcher.matches(&format!("{DIR}/14501.schema.example.sql")));
+}
+
+#[test]
+fn no_patterns_match_nothing() {
+    let matcher = build_move_matcher(&[]).unwrap();
+    assert!(!matcher.matches(""));
+    assert!(!matcher.matches(&format!("{DIR}/1440.data.example.sql")));
+}
+
+#[test]
+fn invalid_pattern_is_an_error() {
+    assert!(matches!(build_move_matcher(&patterns(&["1440", "(1441"])), Err(MkdbError::Pattern(_))));
+}
+
+#[test]
+fn literal_metacharacters() {
+    let matcher = build_literal_matcher(&patterns(&["1440.data", "c++", "(1441"]));
+    assert!(matcher.matches(&format!("{DIR}/1440.data.example.sql")));
+    assert!(!matcher.matches(&format!("{DIR}/1440_data.example.sql")));
+    assert!(matcher.matches(&format!("{DIR}/1443.function.c++.sql")));
+    assert!(!matcher.matches(&format!("{DIR}/1443.function.cc.sql")));
+    assert!(matcher.matches(&format!("{DIR}/(1441).schema.example.sql")));
+}
+
+#[test]
+fn regex_metacharacters() {
+    let matcher = build_move_matcher(&patterns(&["1440.data"])).unwrap();
+    assert!(matcher.matches(&format!("{DIR}/1440_data.example.sql")));
+}
+
+#[test]
+fn partition_keeps_order() {
+    let paths: Vec<String> = (1440..1450).map(|n| format!("{DIR}/{n}.schema.example.sql")).collect();
+    let (kept, moved) = build_move_matcher(&patterns(&["144[37]", "1449"])).unwrap().partition(paths.clone());
+    assert_eq!(moved, vec![paths[3].clone(), paths[7].clone(), paths[9].clone()]);
+    assert_eq!(kept.len(), 7);
+    assert!(kept.windows(2).all(|w| w[0] < w[1]));
+    let (kept, moved) = build_move_matcher(&[]).unwrap().partition(paths.clone());
+    assert_eq!(kept, paths);
+    assert!(moved.is_empty());
+}
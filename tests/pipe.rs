@@ -0,0 +1,48 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+#![cfg(unix)]
+use mkdbupgrade::{MkdbError, pipe_script, run_hook};
+
+#[test]
+fn output_replaces_script() {
+    assert_eq!(pipe_script("sed 's/^select/SELECT/'", b"select 1;\nselect 2;\n").unwrap(), b"SELECT 1;\nSELECT 2;\n");
+}
+
+#[test]
+fn large_scripts_do_not_block() {
+    let script = "SELECT 1;\n".repeat(200_000);
+    assert_eq!(pipe_script("cat", script.as_bytes()).unwrap(), script.as_bytes());
+}
+
+#[test]
+fn failures_are_errors() {
+    assert!(matches!(pipe_script("sh -c 'exit 3'", b"SELECT 1;\n"),
+                     Err(MkdbError::Status { status, .. }) if status.code() == Some(3)));
+    assert!(matches!(pipe_script("/nonexistent/formatter", b"SELECT 1;\n"), Err(MkdbError::Io(_))));
+    assert!(matches!(pipe_script("", b"SELECT 1;\n"), Err(MkdbError::Io(_))));
+}
+
+#[test]
+fn hook_gets_output_path() {
+    let file = "tests/fixtures/1441.schema.example.sql";
+    assert!(run_hook(r#"sh -c '[ "$1" = "$MKDBUPGRADE_OUTPUT" ] && [ -f "$1" ]' hook"#, file).is_ok());
+    assert!(matches!(run_hook("sh -c 'exit 4' hook", file),
+                     Err(MkdbError::Status { status, .. }) if status.code() == Some(4)));
+}
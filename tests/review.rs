@@ -0,0 +1,84 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+#![cfg(unix)]
+use mkdbupgrade::{MkdbError, page_file, review_file};
+use std::env::{remove_var, set_var};
+use std::ffi::OsStr;
+use std::fs::{Permissions, set_permissions, write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+
+const FILE: &str = "tests/fixtures/1441.schema.example.sql";
+
+// The environment is shared by every test in this file, so all of
+// the cases are run from a single test.
+#[test]
+fn review_and_page_file() {
+    unsafe { remove_var("GIT_EDITOR") };
+    unsafe { remove_var("VISUAL") };
+    unsafe { set_var("EDITOR", OsStr::from_bytes(b"\xffeditor")) };
+    assert!(matches!(review_file(FILE), Err(MkdbError::Editor(_))));
+
+    unsafe { set_var("EDITOR", "/nonexistent/editor --wait") };
+    assert!(matches!(review_file(FILE), Err(MkdbError::Io(_))));
+
+    unsafe { set_var("EDITOR", "true") };
+    assert!(review_file(FILE).is_ok());
+
+    unsafe { set_var("EDITOR", "false") };
+    assert!(matches!(review_file(FILE), Err(MkdbError::Status { program, status }) if program == "false" && status.code() == Some(1)));
+
+    // VISUAL is used instead of EDITOR when both are set.
+    unsafe { set_var("VISUAL", "true") };
+    unsafe { set_var("EDITOR", "/nonexistent/editor") };
+    assert!(review_file(FILE).is_ok());
+
+    unsafe { remove_var("EDITOR") };
+    assert!(review_file(FILE).is_ok());
+
+    // GIT_EDITOR comes before both.
+    unsafe { set_var("GIT_EDITOR", "true") };
+    unsafe { set_var("VISUAL", "/nonexistent/editor") };
+    assert!(review_file(FILE).is_ok());
+    unsafe { remove_var("GIT_EDITOR") };
+    assert!(matches!(review_file(FILE), Err(MkdbError::Io(_))));
+    unsafe { remove_var("VISUAL") };
+
+    // An editor with spaces in its path and arguments, which only
+    // succeeds if the arguments arrive intact.
+    let dir = tempfile::tempdir().unwrap();
+    let editor = dir.path().join("my editor");
+    write(&editor, "#!/bin/sh\n[ \"$1\" = \"--flag with space\" ] && [ \"$2\" = \"$3\" ]\n").unwrap();
+    set_permissions(&editor, Permissions::from_mode(0o755)).unwrap();
+    let editor = editor.display();
+    unsafe { set_var("EDITOR", format!("'{editor}' \"--flag with space\" {FILE}")) };
+    assert!(review_file(FILE).is_ok());
+    unsafe { set_var("EDITOR", format!("'{editor}' --flag with space {FILE}")) };
+    assert!(matches!(review_file(FILE), Err(MkdbError::Status { .. })));
+
+    unsafe { set_var("PAGER", "true") };
+    assert!(page_file(FILE).is_ok());
+
+    unsafe { set_var("PAGER", "false") };
+    assert!(matches!(page_file(FILE), Err(MkdbError::Status { .. })));
+
+    unsafe { set_var("PAGER", "/nonexistent/pager") };
+    assert!(matches!(page_file(FILE), Err(MkdbError::Io(_))));
+}
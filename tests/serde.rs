@@ -0,0 +1,43 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+#![cfg(feature = "serde")]
+use mkdbupgrade::{UpgradeDiff, UpgradeEntry};
+
+#[test]
+fn diff_round_trip() {
+    let diff = UpgradeDiff {
+        added: vec!["Open-ILS/src/sql/Pg/upgrade/1441.schema.example.sql".to_string()],
+        removed: Vec::new(),
+        changed: vec!["Open-ILS/src/sql/Pg/upgrade/1440.data.example.sql".to_string()],
+    };
+    let json = serde_json::to_string(&diff).unwrap();
+    assert_eq!(json, r#"{"added":["Open-ILS/src/sql/Pg/upgrade/1441.schema.example.sql"],"removed":[],"changed":["Open-ILS/src/sql/Pg/upgrade/1440.data.example.sql"]}"#);
+    assert_eq!(serde_json::from_str::<UpgradeDiff>(&json).unwrap(), diff);
+}
+
+#[test]
+fn entry_round_trip() {
+    let entry = UpgradeEntry {
+        path: "Open-ILS/src/sql/Pg/upgrade/1441.schema.example.sql".to_string(),
+        oid: "8c53ef71c2b21b65b570713cdc25644d569c0e49".to_string(),
+    };
+    let json = serde_json::to_string(&entry).unwrap();
+    assert_eq!(serde_json::from_str::<UpgradeEntry>(&json).unwrap(), entry);
+}
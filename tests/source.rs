@@ -0,0 +1,268 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use mkdbupgrade::{MkdbError, PrependPosition, ScriptOptions, UpgradeEntry, UpgradeScriptBuilder, UpgradeSource};
+
+/// A source with canned upgrades that reads every file from one fixture
+struct MockSource;
+
+impl UpgradeSource for MockSource {
+    type Ref = &'static str;
+
+    fn upgrades(&self, rev: &&'static str) -> Result<Vec<UpgradeEntry>, MkdbError> {
+        let names: &[&str] = match *rev {
+            "3.14.0" => &["1440.data.example.sql"],
+            "3.14.1" => &["1440.data.example.sql", "1441.schema.example.sql", "1442.function.example.sql"],
+            _ => return Err(MkdbError::Version(rev.to_string())),
+        };
+        Ok(names.iter().map(|n| UpgradeEntry { path: n.to_string(), oid: String::new() }).collect())
+    }
+
+    fn version(&self, rev: &&'static str) -> Result<String, MkdbError> {
+        Ok(rev.to_string())
+    }
+
+    fn file_path(&self, _rev: &&'static str, _path: &str) -> String {
+        "tests/fixtures/1441.schema.example.sql".to_string()
+    }
+}
+
+#[test]
+fn mock_upgrades_and_versions() {
+    let builder = UpgradeScriptBuilder::with_source(MockSource, "3.14.0", "3.14.1")
+        .skipped(vec!["1442".to_string()]);
+    assert_eq!(builder.upgrades().unwrap(), vec!["1441.schema.example.sql"]);
+    assert_eq!(builder.versions().unwrap(), ("3.14.0".to_string(), "3.14.1".to_string()));
+}
+
+#[test]
+fn mock_build() {
+    let builder = UpgradeScriptBuilder::with_source(MockSource, "3.14.0", "3.14.1")
+        .moved(vec!["1442".to_string()]);
+    let mut out: Vec<u8> = Vec::new();
+    let stats = builder.build_to(&mut out).unwrap();
+    assert_eq!(stats.inlined, 1);
+    assert_eq!(stats.moved, 1);
+    let script = String::from_utf8(out).unwrap();
+    assert!(script.starts_with("-- Upgrade script for Evergreen 3.14.0 to 3.14.1\n"));
+    assert_eq!(script.matches("CREATE INDEX foo_idx").count(), 2);
+}
+
+#[test]
+fn mock_errors_pass_through() {
+    let builder = UpgradeScriptBuilder::with_source(MockSource, "3.14.0", "9.9.9");
+    assert!(matches!(builder.render_script(), Err(MkdbError::Version(v)) if v == "9.9.9"));
+}
+
+#[test]
+fn moved_upgrades_verbatim_or_stripped() {
+    let builder = UpgradeScriptBuilder::with_source(MockSource, "3.14.0", "3.14.1").moved(vec!["1442".to_string()]);
+    let verbatim = builder.render_script().unwrap();
+    let moved = &verbatim[verbatim.find("-- Start of moved upgrades").unwrap()..];
+    assert!(moved.starts_with("-- Start of moved upgrades\n--Upgrade Script for 3.14.0 to 3.14.1\n"));
+    assert_eq!(moved.matches("BEGIN;").count(), 1);
+    assert_eq!(moved.matches("COMMIT;").count(), 1);
+
+    let stripped = builder.strip_moved(true).render_script().unwrap();
+    let moved = &stripped[stripped.find("-- Start of moved upgrades").unwrap()..];
+    assert!(moved.starts_with("-- Start of moved upgrades\nBEGIN;\n--Upgrade Script for 3.14.0 to 3.14.1\n"));
+    assert!(moved.contains("CREATE INDEX foo_idx ON actor.usr (id);\n\nCOMMIT;\n-- End of moved upgrades\n"));
+    assert_eq!(moved.matches("BEGIN;").count(), 1);
+    assert_eq!(moved.matches("COMMIT;").count(), 1);
+
+    let options = ScriptOptions { transaction: false, auditor_block: false };
+    let script = UpgradeScriptBuilder::with_source(MockSource, "3.14.0", "3.14.1")
+        .moved(vec!["1442".to_string()])
+        .strip_moved(true)
+        .options(options)
+        .render_script()
+        .unwrap();
+    assert!(!script.contains("BEGIN;"));
+    assert!(!script.contains("COMMIT;"));
+}
+
+#[test]
+fn bad_patterns_fail_before_listing() {
+    // Listing "9.9.9" fails, so the pattern errors must come first.
+    let builder = UpgradeScriptBuilder::with_source(MockSource, "3.14.0", "9.9.9")
+        .version("9.9.9")
+        .moved(vec!["(".to_string()])
+        .move_regex(true);
+    assert!(matches!(builder.render_script(), Err(MkdbError::Pattern(_))));
+    let builder = UpgradeScriptBuilder::with_source(MockSource, "3.14.0", "9.9.9")
+        .skipped(vec!["(".to_string()]);
+    assert!(matches!(builder.upgrades(), Err(MkdbError::Pattern(_))));
+}
+
+/// A source with many upgrades, each in its own temporary file
+struct ManySource {
+    dir: tempfile::TempDir,
+    count: usize,
+}
+
+impl ManySource {
+    /// Write count upgrade files, each selecting its own number
+    fn new(count: usize) -> ManySource {
+        let dir = tempfile::tempdir().unwrap();
+        for n in 0..count {
+            std::fs::write(dir.path().join(format!("{n:04}.data.example.sql")), format!("BEGIN;\nSELECT {n};\nCOMMIT;\n")).unwrap();
+        }
+        ManySource { dir, count }
+    }
+}
+
+impl UpgradeSource for &ManySource {
+    type Ref = bool;
+
+    fn upgrades(&self, to: &bool) -> Result<Vec<UpgradeEntry>, MkdbError> {
+        let count = if *to { self.count } else { 0 };
+        Ok((0..count).map(|n| UpgradeEntry { path: format!("{n:04}.data.example.sql"), oid: String::new() }).collect())
+    }
+
+    fn version(&self, to: &bool) -> Result<String, MkdbError> {
+        Ok(if *to { "3.14.1" } else { "3.14.0" }.to_string())
+    }
+
+    fn file_path(&self, _to: &bool, path: &str) -> String {
+        self.dir.path().join(path).to_string_lossy().into_owned()
+    }
+}
+
+#[test]
+fn many_upgrades_keep_their_order() {
+    let count = 200;
+    let source = ManySource::new(count);
+    let script = UpgradeScriptBuilder::with_source(&source, false, true)
+        .moved((150..count).map(|n| format!("{n:04}.")).collect())
+        .render_script()
+        .unwrap();
+    let selects: Vec<&str> = script.lines().filter(|l| l.starts_with("SELECT ") && !l.contains("auditor")).collect();
+    let expected: Vec<String> = (0..count).map(|n| format!("SELECT {n};")).collect();
+    assert_eq!(selects, expected);
+    // Only the moved upgrades keep their transactions.
+    assert_eq!(script.matches("BEGIN;").count(), 51);
+}
+
+#[test]
+fn many_upgrades_with_jobs() {
+    let count = 100;
+    let source = ManySource::new(count);
+    let expected = UpgradeScriptBuilder::with_source(&source, false, true).render_script().unwrap();
+    for jobs in [1, 2, 7] {
+        let script = UpgradeScriptBuilder::with_source(&source, false, true).jobs(jobs).render_script().unwrap();
+        assert_eq!(script, expected, "{jobs} jobs");
+    }
+    for threshold in [0, 1, count, count + 1] {
+        let script = UpgradeScriptBuilder::with_source(&source, false, true)
+            .jobs(2)
+            .parallel_threshold(threshold)
+            .render_script()
+            .unwrap();
+        assert_eq!(script, expected, "threshold {threshold}");
+    }
+}
+
+#[test]
+fn mostly_moved_upgrades() {
+    // Neither list reaches the threshold alone, but both together do.
+    let count = 40;
+    let source = ManySource::new(count);
+    let moved: Vec<String> = (20..count).map(|n| format!("{n:04}.")).collect();
+    let builder = UpgradeScriptBuilder::with_source(&source, false, true).moved(moved.clone()).parallel_threshold(30);
+    let script = builder.render_script().unwrap();
+    let sequential = builder.jobs(1).render_script().unwrap();
+    assert_eq!(script, sequential);
+    assert_eq!(script.matches("BEGIN;").count(), 21);
+    assert!(script.find("SELECT 19;").unwrap() < script.find("-- Start of moved upgrades").unwrap());
+    assert!(script.find("SELECT 20;").unwrap() > script.find("-- Start of moved upgrades").unwrap());
+}
+
+#[test]
+fn many_upgrades_missing_file() {
+    let source = ManySource { dir: tempfile::tempdir().unwrap(), count: 100 };
+    let result = UpgradeScriptBuilder::with_source(&source, false, true).render_script();
+    assert!(matches!(result, Err(MkdbError::File { .. })));
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mapped_upgrades_match_read_upgrades() {
+    let count = 40;
+    let source = ManySource::new(count);
+    let read = UpgradeScriptBuilder::with_source(&source, false, true).render_script().unwrap();
+    let mapped = UpgradeScriptBuilder::with_source(&source, false, true).mmap_threshold(0).render_script().unwrap();
+    assert_eq!(mapped, read);
+}
+
+#[test]
+fn appended_files_keep_their_boundaries() {
+    let script = UpgradeScriptBuilder::with_source(MockSource, "3.14.0", "3.14.1")
+        .append_files(vec!["tests/fixtures/no-final-newline.sql".to_string(), "tests/fixtures/no-final-newline.sql".to_string()])
+        .render_script()
+        .unwrap();
+    assert!(script.ends_with("SET search_path = evergreen, public;\n-- Prepended without a final newline\nSET search_path = evergreen, public;\n-- End of appended code\n"));
+}
+
+#[test]
+fn prepended_files_before_or_inside_the_transaction() {
+    let builder = UpgradeScriptBuilder::with_source(MockSource, "3.14.0", "3.14.1")
+        .prepend_files(vec!["tests/fixtures/no-final-newline.sql".to_string()]);
+    let before = builder.render_script().unwrap();
+    assert!(before.starts_with("-- Start of prepended code\n-- Prepended without a final newline\n"));
+    assert!(before.find("-- End of prepended code").unwrap() < before.find("BEGIN;").unwrap());
+
+    let inside = builder.prepend_position(PrependPosition::InsideTransaction).render_script().unwrap();
+    assert!(inside.starts_with("-- Upgrade script for Evergreen 3.14.0 to 3.14.1\n"));
+    assert!(inside.contains("\nBEGIN;\n-- Start of prepended code\n"));
+    assert!(inside.find("-- End of prepended code").unwrap() < inside.find("CREATE INDEX foo_idx").unwrap());
+    assert_eq!(inside.matches("BEGIN;").count(), 1);
+}
+
+#[test]
+fn empty_upgrades_with_allow_empty() {
+    let source = ManySource::new(0);
+    let builder = UpgradeScriptBuilder::with_source(&source, false, true);
+    assert!(matches!(builder.render_script(), Err(MkdbError::NoUpgrades)));
+    let mut out: Vec<u8> = Vec::new();
+    let stats = builder.allow_empty(true).build_to(&mut out).unwrap();
+    assert_eq!((stats.inlined, stats.moved), (0, 0));
+    let script = String::from_utf8(out).unwrap();
+    assert!(script.starts_with("-- Upgrade script for Evergreen 3.14.0 to 3.14.1\n"));
+    assert!(script.contains("\nBEGIN;\nCOMMIT;\n"));
+    assert!(script.contains("auditor"));
+}
+
+#[test]
+fn unreadable_files_are_all_reported() {
+    let builder = UpgradeScriptBuilder::with_source(MockSource, "3.14.0", "3.14.1")
+        .prepend_files(vec!["tests/fixtures/no-such-prepend.sql".to_string(), "tests/fixtures/no-final-newline.sql".to_string()])
+        .append_files(vec!["tests/fixtures".to_string(), "tests/fixtures/no-such-append.sql".to_string()]);
+    let mut out: Vec<u8> = Vec::new();
+    let Err(MkdbError::Files(errors)) = builder.build_to(&mut out) else {
+        panic!("the unreadable files were not reported");
+    };
+    let paths: Vec<&str> = errors.iter()
+        .map(|e| match e {
+            MkdbError::File { path, .. } => path.as_str(),
+            _ => panic!("{e}"),
+        })
+        .collect();
+    assert_eq!(paths, vec!["tests/fixtures/no-such-prepend.sql", "tests/fixtures", "tests/fixtures/no-such-append.sql"]);
+    assert!(out.is_empty());
+}
@@ -0,0 +1,150 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use mkdbupgrade::{MkdbError, UpgradeEntry, UpgradeScriptBuilder, UpgradeSource, make_test_script, test_run_script};
+
+#[test]
+fn test_script_rolls_back() {
+    let script = "\
+-- Upgrade script for Evergreen 3.14.0 to 3.14.1
+\\set eg_version '''3.14.1'''
+
+BEGIN;
+SELECT 1;
+COMMIT;
+
+-- Start of moved upgrades
+  BEGIN;
+SELECT 2;
+COMMIT;
+-- End of moved upgrades
+";
+    assert_eq!(make_test_script(script), "\
+BEGIN;
+-- Upgrade script for Evergreen 3.14.0 to 3.14.1
+\\set eg_version '''3.14.1'''
+
+SELECT 1;
+
+-- Start of moved upgrades
+SELECT 2;
+-- End of moved upgrades
+ROLLBACK;
+");
+}
+
+#[test]
+fn any_commit_is_removed() {
+    let script = "\
+BEGIN;
+SELECT 1;
+COMMIT;
+-- Start of appended code
+commit;
+COMMIT ;
+Commit Work;
+end transaction;
+END;
+start transaction isolation level serializable;
+ROLLBACK;
+abort;
+-- End of appended code
+";
+    assert_eq!(make_test_script(script), "\
+BEGIN;
+SELECT 1;
+-- Start of appended code
+-- End of appended code
+ROLLBACK;
+");
+}
+
+/// A source with one upgrade, read from the fixture
+struct OneUpgrade;
+
+impl UpgradeSource for OneUpgrade {
+    type Ref = bool;
+
+    fn upgrades(&self, to: &bool) -> Result<Vec<UpgradeEntry>, MkdbError> {
+        let names: &[&str] = if *to { &["1441.schema.example.sql"] } else { &[] };
+        Ok(names.iter().map(|n| UpgradeEntry { path: n.to_string(), oid: String::new() }).collect())
+    }
+
+    fn version(&self, to: &bool) -> Result<String, MkdbError> {
+        Ok(if *to { "3.14.1" } else { "3.14.0" }.to_string())
+    }
+
+    fn file_path(&self, _to: &bool, path: &str) -> String {
+        format!("tests/fixtures/{path}")
+    }
+}
+
+#[test]
+fn appended_commit_is_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    let appended = dir.path().join("append.sql");
+    std::fs::write(&appended, "begin;\nUPDATE actor.usr SET active = FALSE;\ncommit;\n").unwrap();
+    let script = UpgradeScriptBuilder::with_source(OneUpgrade, false, true)
+        .append_files(vec![appended.to_str().unwrap().to_string()])
+        .render_script()
+        .unwrap();
+    assert!(script.contains("\ncommit;\n"));
+    let test = make_test_script(&script);
+    assert!(test.contains("UPDATE actor.usr SET active = FALSE;\n"));
+    assert!(test.ends_with("\nROLLBACK;\n"));
+    let ends: Vec<&str> = test.lines().filter(|l| l.to_uppercase().starts_with("COMMIT")).collect();
+    assert!(ends.is_empty(), "{ends:?}");
+    assert_eq!(test.lines().filter(|l| l.to_uppercase().starts_with("BEGIN")).count(), 1);
+}
+
+#[test]
+fn function_bodies_are_kept() {
+    let script = "\
+BEGIN;
+CREATE FUNCTION evergreen.example() RETURNS INT AS $func$
+BEGIN
+    RETURN 1;
+END;
+$func$ LANGUAGE PLPGSQL;
+DO $$ BEGIN PERFORM 1; END $$;
+end;
+ROLLBACK TO SAVEPOINT before_example;
+";
+    assert_eq!(make_test_script(script), "\
+BEGIN;
+CREATE FUNCTION evergreen.example() RETURNS INT AS $func$
+BEGIN
+    RETURN 1;
+END;
+$func$ LANGUAGE PLPGSQL;
+DO $$ BEGIN PERFORM 1; END $$;
+ROLLBACK TO SAVEPOINT before_example;
+ROLLBACK;
+");
+}
+
+#[test]
+fn unreachable_database_fails() {
+    // Io if psql is not installed.
+    match test_run_script("host=/nonexistent dbname=evergreen connect_timeout=1", "SELECT 1;\n") {
+        Err(MkdbError::TestRun { errors, .. }) => assert!(!errors.is_empty()),
+        Err(MkdbError::Io(_)) => (),
+        other => panic!("expected an error, got {:?}", other),
+    }
+}
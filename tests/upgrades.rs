@@ -0,0 +1,45 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use mkdbupgrade::{UpgradeEntry, diff_upgrades, new_upgrades};
+
+fn entry(n: usize, oid: usize) -> UpgradeEntry {
+    UpgradeEntry { path: format!("{:05}.schema.example.sql", n), oid: format!("{:040x}", oid) }
+}
+
+#[test]
+fn large_listings() {
+    let from: Vec<UpgradeEntry> = (0..20_000).map(|n| entry(n, n)).collect();
+    // Drop every tenth upgrade, change every seventh, and add more.
+    let to: Vec<UpgradeEntry> = (0..21_000)
+        .filter(|n| n % 10 != 5)
+        .map(|n| entry(n, if n % 7 == 0 { n + 1 } else { n }))
+        .collect();
+
+    let new = new_upgrades(&from, &to);
+    assert_eq!(new.len(), 900);
+    assert_eq!(new.first().map(String::as_str), Some("20000.schema.example.sql"));
+    assert_eq!(new.last().map(String::as_str), Some("20999.schema.example.sql"));
+
+    let diff = diff_upgrades(&from, &to);
+    assert_eq!(diff.added, new);
+    assert_eq!(diff.removed.len(), 2000);
+    assert_eq!(diff.removed[0], "00005.schema.example.sql");
+    assert_eq!(diff.changed.len(), (0..20_000).filter(|n| n % 7 == 0 && n % 10 != 5).count());
+}
@@ -0,0 +1,56 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use mkdbupgrade::validate_script;
+
+const PREAMBLE: &str = "-- Upgrade script for Evergreen 3.14.0 to 3.14.1\n\\set eg_version '''3.14.1'''\n";
+
+#[test]
+fn sound_script() {
+    let script = format!("{PREAMBLE}\nBEGIN;\nSELECT 1;\nCOMMIT;\n\n-- Start of moved upgrades\nbegin;\nSELECT 2;\n  commit ;\n-- End of moved upgrades\n");
+    assert!(validate_script(&script).is_empty());
+}
+
+#[test]
+fn missing_preamble() {
+    assert_eq!(validate_script("BEGIN;\nCOMMIT;\n"),
+               vec!["the upgrade script comment is missing", "the eg_version variable is not set"]);
+}
+
+#[test]
+fn unbalanced_transactions() {
+    let script = format!("{PREAMBLE}\nBEGIN;\nSELECT 1;\nBEGIN;\nCOMMIT;\nCOMMIT;\nROLLBACK;\n");
+    assert_eq!(validate_script(&script), vec![
+        "BEGIN on line 6 is inside the transaction begun on line 4",
+        "COMMIT on line 8 has no matching BEGIN",
+        "ROLLBACK on line 9 has no matching BEGIN",
+    ]);
+}
+
+#[test]
+fn plpgsql_begin_is_not_a_transaction() {
+    let script = format!("{PREAMBLE}\nBEGIN;\nCREATE FUNCTION f() RETURNS INT AS $$\nBEGIN\n    RETURN 1;\nEND;\n$$ LANGUAGE plpgsql;\nCOMMIT;\n");
+    assert!(validate_script(&script).is_empty());
+}
+
+#[test]
+fn other_transaction_statements() {
+    let script = format!("{PREAMBLE}\nSTART TRANSACTION;\nSELECT 1;\nEND;\nbegin work;\nCOMMIT TRANSACTION;\nend;\n");
+    assert_eq!(validate_script(&script), vec!["END on line 9 has no matching BEGIN"]);
+}
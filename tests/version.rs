@@ -0,0 +1,41 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use mkdbupgrade::normalize_version;
+use std::borrow::Cow;
+
+#[test]
+fn normalized_versions_are_borrowed() {
+    for v in ["3.14.1", "3.7.10", "10.0.0"] {
+        assert!(matches!(normalize_version(v), Some(Cow::Borrowed(b)) if b == v));
+    }
+}
+
+#[test]
+fn underscore_versions_are_converted() {
+    assert_eq!(normalize_version("3_14_1").as_deref(), Some("3.14.1"));
+    assert_eq!(normalize_version("rel_3_7_10").as_deref(), Some("3.7.10"));
+}
+
+#[test]
+fn invalid_versions() {
+    for v in ["", "3.14", "3.14.1.2", "3.14.x", "3.100.1", "rel_3.14.1", "tag_3_14_1"] {
+        assert_eq!(normalize_version(v), None, "{v}");
+    }
+}
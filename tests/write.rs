@@ -0,0 +1,197 @@
+/*
+ * Copyright © 2025 C/W MARS, Inc.
+ * Author: Jason Stephenson <jason@sigio.com>
+ *
+ * This file is part of mkdbupgrade.
+ *
+ * mkdbupgrade is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * mkdbupgrade is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with mkdbupgrade.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use mkdbupgrade::{OutputOptions, ScriptOptions, write_auditor_block, write_file, write_preamble, write_upgrade};
+use std::fs::read;
+use std::io::{self, Write};
+
+const UPGRADE: &str = "tests/fixtures/1441.schema.example.sql";
+const NO_NEWLINE: &str = "tests/fixtures/no-final-newline.sql";
+
+#[test]
+fn write_upgrade_strips_begin_and_commit() {
+    let mut out: Vec<u8> = Vec::new();
+    write_upgrade(&mut out, UPGRADE).unwrap();
+    let expected = "\
+--Upgrade Script for 3.14.0 to 3.14.1
+
+SELECT evergreen.upgrade_deps_block_check('1441', :eg_version);
+
+CREATE INDEX foo_idx ON actor.usr (id);
+
+";
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+}
+
+#[test]
+fn write_upgrade_missing_file() {
+    let mut out: Vec<u8> = Vec::new();
+    assert!(write_upgrade(&mut out, "tests/fixtures/no-such-upgrade.sql").is_err());
+    assert!(out.is_empty());
+}
+
+#[test]
+fn write_file_copies_contents() {
+    let mut out: Vec<u8> = Vec::new();
+    write_file(&mut out, UPGRADE).unwrap();
+    assert_eq!(out, read(UPGRADE).unwrap());
+}
+
+#[test]
+fn write_file_ends_with_a_newline() {
+    let mut out: Vec<u8> = Vec::new();
+    write_file(&mut out, NO_NEWLINE).unwrap();
+    write_file(&mut out, UPGRADE).unwrap();
+    let mut expected = read(NO_NEWLINE).unwrap();
+    assert_ne!(expected.last(), Some(&b'\n'));
+    expected.push(b'\n');
+    expected.extend(read(UPGRADE).unwrap());
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn write_file_empty_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("empty.sql");
+    std::fs::write(&path, "").unwrap();
+    let mut out: Vec<u8> = Vec::new();
+    write_file(&mut out, path.to_str().unwrap()).unwrap();
+    assert!(out.is_empty());
+}
+
+#[test]
+fn write_upgrade_keeps_line_endings() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("1442.function.example.sql");
+    std::fs::write(&path, "BEGIN;\r\nSELECT 1;\r\nCOMMIT;\r\nSELECT 2;").unwrap();
+    let mut out: Vec<u8> = Vec::new();
+    write_upgrade(&mut out, path.to_str().unwrap()).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "SELECT 1;\r\nSELECT 2;\n");
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn write_upgrade_mapped_matches_write_upgrade() {
+    let dir = tempfile::tempdir().unwrap();
+    let contents = [
+        "",
+        "\n",
+        "BEGIN;\nSELECT 1;\nCOMMIT;\n",
+        "BEGIN;\r\nSELECT 1;\r\nCOMMIT;\r\nSELECT 2;",
+        "  BEGIN;\n\nSELECT 'COMMIT;';\n\n  COMMIT;\n\n",
+    ];
+    for (n, text) in contents.iter().enumerate() {
+        let path = dir.path().join(format!("{n}.sql"));
+        std::fs::write(&path, text).unwrap();
+        let (mut read, mut mapped): (Vec<u8>, Vec<u8>) = (Vec::new(), Vec::new());
+        write_upgrade(&mut read, path.to_str().unwrap()).unwrap();
+        mkdbupgrade::write_upgrade_mapped(&mut mapped, path.to_str().unwrap()).unwrap();
+        assert_eq!(mapped, read, "{text:?}");
+    }
+    let mut out: Vec<u8> = Vec::new();
+    assert!(mkdbupgrade::write_upgrade_mapped(&mut out, "tests/fixtures/no-such-upgrade.sql").is_err());
+}
+
+/// Writer that counts the calls made to write
+#[derive(Default)]
+struct WriteCounter {
+    writes: usize,
+    bytes: Vec<u8>,
+}
+
+impl Write for WriteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn preamble_and_auditor_block_are_written_at_once() {
+    let options = ScriptOptions::default();
+    let mut out = WriteCounter::default();
+    write_preamble(&mut out, "3.14.0", "3.14.1", &options).unwrap();
+    assert_eq!(out.writes, 1);
+    write_auditor_block(&mut out, &options).unwrap();
+    assert_eq!(out.writes, 2);
+    assert!(String::from_utf8(out.bytes).unwrap().ends_with("BEGIN;\n-- Update auditor tables to catch changes in source tables.\n-- Can be removed/skipped if there were no schema changes.\nSELECT auditor.update_auditors();\n"));
+
+    let options = ScriptOptions { transaction: false, auditor_block: false };
+    let mut out = WriteCounter::default();
+    write_preamble(&mut out, "3.14.0", "3.14.1", &options).unwrap();
+    write_auditor_block(&mut out, &options).unwrap();
+    assert_eq!(out.writes, 1);
+    assert_eq!(String::from_utf8(out.bytes).unwrap(), "-- Upgrade script for Evergreen 3.14.0 to 3.14.1\n\\set eg_version '''3.14.1'''\n");
+}
+
+#[test]
+fn write_script_failure_leaves_nothing_behind() {
+    let dir = tempfile::tempdir().unwrap();
+    // The name just fits, but the temporary file's longer name does
+    // not, so the script cannot be written after the path is claimed.
+    let options = OutputOptions { directory: dir.path().to_path_buf(), prefix: "x".repeat(220), ..Default::default() };
+    let path = options.path("3.14.0", "3.14.1");
+    let e = options.write_script(&path, b"SELECT 1;\n").unwrap_err();
+    assert_ne!(e.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    // A directory in the way makes the rename fail, and it is left as
+    // it was.
+    let options = OutputOptions { directory: dir.path().to_path_buf(), clobber: true, ..Default::default() };
+    let path = options.path("3.14.0", "3.14.1");
+    std::fs::create_dir(&path).unwrap();
+    std::fs::write(path.join("keep.sql"), "-- keep me\n").unwrap();
+    let e = options.write_script(&path, b"SELECT 1;\n").unwrap_err();
+    assert_ne!(e.kind(), io::ErrorKind::AlreadyExists);
+    let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(names, vec![path.file_name().unwrap()]);
+    assert_eq!(std::fs::read_to_string(path.join("keep.sql")).unwrap(), "-- keep me\n");
+}
+
+#[test]
+fn write_script_with_failure_leaves_nothing_behind() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = OutputOptions { directory: dir.path().to_path_buf(), ..Default::default() };
+    let path = options.path("3.14.0", "3.14.1");
+    // Failing before anything is written touches nothing.
+    let e = options.write_script_with(&path, |_| Err::<(), _>(io::Error::other("no upgrades"))).unwrap_err();
+    assert_eq!(e.to_string(), "no upgrades");
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    // Failing part way through removes both the temporary file and
+    // the claimed path.
+    let e = options.write_script_with(&path, |out| {
+        out.write_all(b"BEGIN;\n")?;
+        out.flush()?;
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+        Err::<(), _>(io::Error::other("unreadable upgrade"))
+    }).unwrap_err();
+    assert_eq!(e.to_string(), "unreadable upgrade");
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    let written = options.write_script_with(&path, |out| out.write_all(b"SELECT 1;\n").map(|_| 10)).unwrap();
+    assert_eq!(written, 10);
+    assert_eq!(read(&path).unwrap(), b"SELECT 1;\n");
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}